@@ -0,0 +1,252 @@
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::error::Error;
+
+use crate::op::OpTrait;
+use crate::simulator::SimConfig;
+
+pub trait CostModel {
+    fn cost(&self, op: &dyn OpTrait) -> usize;
+}
+
+// CrossPOp by k*m*n, VecOp by length, TransOp by length / bandwidth plus hops,
+// ReconfOp fixed.
+impl CostModel for SimConfig {
+    fn cost(&self, op: &dyn OpTrait) -> usize {
+        self.latency(op)
+    }
+}
+
+// Topological order of op_list positions, fails on dangling deps or cycles.
+pub fn topo_order(op_list: &[Box<dyn OpTrait>]) -> Result<Vec<usize>, Box<dyn Error>> {
+    let mut pos: HashMap<usize, usize> = HashMap::new();
+    for (i, op) in op_list.iter().enumerate() {
+        if pos.insert(op.index(), i).is_some() {
+            return Err(format!("Duplicated op index {}", op.index()).into());
+        }
+    }
+    let mut indegree = vec![0; op_list.len()];
+    let mut succs: Vec<Vec<usize>> = vec![vec![]; op_list.len()];
+    for (i, op) in op_list.iter().enumerate() {
+        for dep in op.dependency() {
+            let dep_pos =
+                pos.get(dep)
+                    .ok_or(format!("Op {} depends on unknown op {}", op.index(), dep))?;
+            succs[*dep_pos].push(i);
+            indegree[i] += 1;
+        }
+    }
+    let mut queue: VecDeque<usize> = (0..op_list.len()).filter(|i| indegree[*i] == 0).collect();
+    let mut order = vec![];
+    while let Some(i) = queue.pop_front() {
+        order.push(i);
+        for s in succs[i].iter() {
+            indegree[*s] -= 1;
+            if indegree[*s] == 0 {
+                queue.push_back(*s);
+            }
+        }
+    }
+    if order.len() != op_list.len() {
+        let stuck = (0..op_list.len())
+            .filter(|i| indegree[*i] > 0)
+            .map(|i| op_list[i].index())
+            .collect::<Vec<_>>();
+        return Err(format!("Dependency cycle among ops {:?}", stuck).into());
+    }
+    Ok(order)
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct OpTiming {
+    pub cost: usize,
+    pub earliest_start: usize,
+    pub latest_start: usize,
+    pub slack: usize,
+}
+
+#[derive(Debug, Default, Clone, Copy)]
+pub struct StepStat {
+    pub ops: usize,
+    pub cost: usize,
+    pub critical_ops: usize,
+    pub critical_cost: usize,
+}
+
+// Resource-unconstrained critical path method over the op DAG.
+pub struct CriticalPathAnalysis {
+    pub makespan: usize,
+    // Op index -> timing.
+    pub timing: BTreeMap<usize, OpTiming>,
+    pub critical_path: Vec<usize>,
+}
+
+impl CriticalPathAnalysis {
+    pub fn analyze(
+        op_list: &[Box<dyn OpTrait>],
+        cost_model: &dyn CostModel,
+    ) -> Result<CriticalPathAnalysis, Box<dyn Error>> {
+        let order = topo_order(op_list)?;
+        let pos: HashMap<usize, usize> = op_list
+            .iter()
+            .enumerate()
+            .map(|(i, op)| (op.index(), i))
+            .collect();
+        let cost = op_list
+            .iter()
+            .map(|op| cost_model.cost(op.as_ref()))
+            .collect::<Vec<_>>();
+        let mut succs: Vec<Vec<usize>> = vec![vec![]; op_list.len()];
+        // Forward pass.
+        let mut es = vec![0; op_list.len()];
+        for i in order.iter() {
+            for dep in op_list[*i].dependency() {
+                let d = pos[dep];
+                es[*i] = es[*i].max(es[d] + cost[d]);
+                succs[d].push(*i);
+            }
+        }
+        let makespan = (0..op_list.len())
+            .map(|i| es[i] + cost[i])
+            .max()
+            .unwrap_or(0);
+        // Backward pass.
+        let mut ls = vec![0; op_list.len()];
+        for i in order.iter().rev() {
+            let lf = succs[*i].iter().map(|s| ls[*s]).min().unwrap_or(makespan);
+            ls[*i] = lf - cost[*i];
+        }
+
+        let mut timing = BTreeMap::new();
+        for (i, op) in op_list.iter().enumerate() {
+            timing.insert(
+                op.index(),
+                OpTiming {
+                    cost: cost[i],
+                    earliest_start: es[i],
+                    latest_start: ls[i],
+                    slack: ls[i] - es[i],
+                },
+            );
+        }
+        // Walk zero-slack ops from a source to the sink.
+        let mut critical_path = vec![];
+        let mut cur = order
+            .iter()
+            .find(|i| es[**i] == 0 && ls[**i] == 0 && op_list[**i].dependency().is_empty())
+            .copied();
+        while let Some(i) = cur {
+            critical_path.push(op_list[i].index());
+            cur = succs[i]
+                .iter()
+                .find(|s| ls[**s] == es[**s] && es[**s] == es[i] + cost[i])
+                .copied();
+        }
+
+        Ok(CriticalPathAnalysis {
+            makespan,
+            timing,
+            critical_path,
+        })
+    }
+
+    // Aggregate ops into steps given by `classify`, e.g. `OuterProduct::exec_step`.
+    pub fn step_breakdown(
+        &self,
+        op_list: &[Box<dyn OpTrait>],
+        classify: impl Fn(&dyn OpTrait) -> Option<usize>,
+    ) -> BTreeMap<usize, StepStat> {
+        let on_path = self.critical_path.iter().collect::<HashSet<_>>();
+        let mut steps: BTreeMap<usize, StepStat> = BTreeMap::new();
+        for op in op_list.iter() {
+            let step = match classify(op.as_ref()) {
+                Some(s) => s,
+                None => continue,
+            };
+            let cost = self.timing[&op.index()].cost;
+            let stat = steps.entry(step).or_default();
+            stat.ops += 1;
+            stat.cost += cost;
+            if on_path.contains(&op.index()) {
+                stat.critical_ops += 1;
+                stat.critical_cost += cost;
+            }
+        }
+        steps
+    }
+
+    pub fn print_summary(&self) {
+        let critical_ops = self.timing.values().filter(|t| t.slack == 0).count();
+        println!("Makespan: {}", self.makespan);
+        println!(
+            "Critical path: {} ops, {} zero-slack ops of {}",
+            self.critical_path.len(),
+            critical_ops,
+            self.timing.len()
+        );
+        // Ops closest to becoming critical.
+        let mut near = self
+            .timing
+            .iter()
+            .filter(|(_, t)| t.slack > 0)
+            .collect::<Vec<_>>();
+        near.sort_by_key(|(idx, t)| (t.slack, **idx));
+        if !near.is_empty() {
+            println!("Least slack off the critical path:");
+        }
+        for (idx, t) in near.iter().take(5) {
+            println!(
+                "  op {:>6} cost {:>8} earliest start {:>10} latest start {:>10} slack {:>8}",
+                idx, t.cost, t.earliest_start, t.latest_start, t.slack
+            );
+        }
+    }
+}
+
+pub fn print_step_breakdown(steps: &BTreeMap<usize, StepStat>, names: &[&str]) {
+    for (step, stat) in steps.iter() {
+        println!(
+            "Step {:>2} {:<22} ops {:>6} cost {:>10} critical ops {:>5} critical cost {:>10}",
+            step,
+            names.get(step - 1).unwrap_or(&"-"),
+            stat.ops,
+            stat.cost,
+            stat.critical_ops,
+            stat.critical_cost
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures::vec_op;
+
+    // Cost is the op's length.
+    struct Length;
+
+    impl CostModel for Length {
+        fn cost(&self, op: &dyn OpTrait) -> usize {
+            op.complexity()
+        }
+    }
+
+    #[test]
+    fn diamond() {
+        // 0 -> {1, 2} -> 3, the long branch through 1 is critical.
+        let ops = vec![
+            vec_op(0, 0, 2, &[]),
+            vec_op(1, 1, 5, &[0]),
+            vec_op(2, 2, 3, &[0]),
+            vec_op(3, 0, 1, &[1, 2]),
+        ];
+        let cpa = CriticalPathAnalysis::analyze(&ops, &Length).unwrap();
+        assert_eq!(cpa.makespan, 8);
+        assert_eq!(cpa.critical_path, vec![0, 1, 3]);
+        let starts = cpa
+            .timing
+            .values()
+            .map(|t| (t.earliest_start, t.latest_start, t.slack))
+            .collect::<Vec<_>>();
+        assert_eq!(starts, vec![(0, 0, 0), (2, 2, 0), (2, 4, 2), (7, 7, 0)]);
+    }
+}
@@ -0,0 +1,929 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::error::Error;
+use std::fs;
+use std::path::Path;
+use std::str::FromStr;
+
+use serde_json::Value;
+
+use crate::analysis::{self, CriticalPathAnalysis};
+use crate::config::{
+    Conv2dConfig, Experiment, GemmConfig, HardwareConfig, WordCountConfig, WorkloadConfig,
+};
+use crate::conv::Conv2d;
+use crate::dram::DramConfig;
+use crate::dse::{self, DesignSpace, Dse, Sampling};
+use crate::energy::EnergyModel;
+use crate::gemm_job::GemmMapReduce;
+use crate::mapreduce::{AllocPolicy, Overflow};
+use crate::noc::{NocTopology, Routing};
+use crate::op::OpTrait;
+use crate::outer_product::{Chain, OuterProduct, Realloc, ReducerGrid, Tiling, EXEC_STEPS};
+use crate::simulator::Simulator;
+use crate::topology::Placement;
+use crate::trace::{self, TraceHeader};
+use crate::word_count::WordCount;
+
+pub const USAGE: &str = "Usage: mapreduce <command> [options]
+
+Commands:
+  outer-product   Generate the outer-product GEMM trace.
+      --mapper-num N          [8]
+      --reducer-num N         [8]
+      --tile-sram-size N      [4096]
+      --amorph-sram           Lend idle mapper sram to reducers.
+      --alloc-policy P        equal | demand-capped | proportional | min-spill [equal]
+      --placement P           sequential | nearest, mappers a reducer borrows from first [sequential]
+      --mult-array MxN        [32x32]
+      --add-array MxN         [64x32]
+      --gemm M,N,K            [128,128,512]
+      --mapper-k N            [4]
+      --reducer-grid G        square | rows | cols | PxQ [square]
+      --auto-tiling           Search mapper-k and reducer grid.
+      --realloc R             static | N, re-allocate remote sram every N k-iterations [static]
+      --then M,N,K;...        GEMMs to run after --gemm in the same trace.
+      --chain C               batch | memory | on-chip, whether each --then GEMM takes the
+                              previous C as A, through memory or from the reducers [memory]
+      --compare-amorph        Also report the critical path with amorph-sram flipped.
+      --verify                Carry random matrices through the dataflow and check C = A x B.
+      --seed N                Seed of the --verify matrices [1]
+      --record-shuffle        Run the GEMM as a MapReduceJob that shuffles partial-sum tiles
+                              as records, like word-count, on the same tiling. Takes no
+                              --then, --realloc or --compare-amorph.
+  conv2d          Generate the trace of a convolution lowered by im2col onto outer-product.
+      --conv N,C,H,W,K,R,S    [1,64,28,28,64,3,3]
+      --stride N              [1]
+      --padding N             Zero padding on each side [0]
+      --verify                Check the lowered GEMM against a direct convolution.
+      Hardware, tiling, --seed and report options as for outer-product.
+  word-count      Generate the word-count trace.
+      --input PATH            [article/1.txt]
+      --mapper-num N          [4]
+      --reducer-num N         [4]
+      --mapper-buf-size N     [4]
+      --reducer-buf-size N    Reducer buffer capacity in records [64]
+      --overflow O            spill | stall, when a reducer buffer is full [spill]
+      --amorph-sram           Full reducers borrow idle mapper sram.
+      --mapper-spare N        Records of its tile-sram-size each mapper lends under
+                              --amorph-sram [reducer-buf-size]
+      --partitioner P         last-char | hash | range | first-char [last-char], the per-reducer
+                              load lands in the trace header
+      --combiner              Pre-aggregate counts in mappers before the shuffle.
+      --counts PATH           Write the word -> count table as JSON.
+      --verify                Check counts against a sequential reference.
+  dse             Sweep outer-product parameters, every option takes a comma list.
+      --mapper-num, --reducer-num, --tile-sram-size, --mult-array, --add-array, --gemm
+      --amorph-sram LIST      e.g. false,true
+      --sampling S            grid | random:N | lhs:N [grid]
+      --seed N                [1]
+      --output PATH           .csv or .json [result/dse.csv]
+  run <config>    Run the experiment described by a JSON config, see configs/. Hardware and
+                  workload come from the config, only --output, --format, --print-ops,
+                  --verbose, --simulate, --analyze, --energy, --compare-amorph, --verify,
+                  --seed and --counts apply.
+  validate <trace>  Check a trace file against the schema.
+  simulate        Re-simulate a saved trace of any schema version.
+      --trace PATH            Trace to load, it is validated first.
+      --analyze, --energy, --energy-cost, --tile-sram-size and the throughput, noc and dram
+      options as for outer-product, every module of the trace counts as a tile.
+
+Common options, unknown options are an error:
+  --output PATH           Trace path [result/<workload>.json].
+  --format F              json | json-v1 | text [json], json-v1 is the legacy bare op list
+                          without kinds, hops, lent or energy
+  --print-ops             Print the format_op listing.
+  --verbose               Print tiling, remote allocation and per-split progress.
+  --simulate              Report cycles from the simulator.
+  --analyze               Report critical path and slack.
+  --energy                Report energy breakdown, EDP and area, and add each op's pJ and the
+                          report to the trace. configs/outer-product-energy.json lists the
+                          cost table a config's hardware energy block can override.
+  --energy-cost K=V,...   Override costs of the table, e.g. mac=0.8,dram_byte=15
+  --link-bw N             Link elements per cycle [64]
+  --dram-bw N             Memory elements per cycle [32]
+  --hop-latency N         Router cycles per mesh hop [1]
+  --noc T                 mesh | torus | crossbar, route transfers over contended links
+                          of link-bw, --simulate then reports congestion and link heat-map
+  --routing R             xy | adaptive [xy]
+  --dram-channels N       Model memory as N channels of dram-bw each, with the options below
+  --dram-latency N        Cycles from request to first data [40]
+  --dram-burst N          Elements per burst [16]
+  --dram-row-size N       Elements per row [1024]
+  --dram-row-miss N       Cycles to activate another row [20]";
+
+pub struct Args {
+    pub command: String,
+    positional: Vec<String>,
+    options: HashMap<String, String>,
+    switches: HashSet<String>,
+}
+
+// Options that take no value.
+const SWITCHES: [&str; 11] = [
+    "amorph-sram",
+    "auto-tiling",
+    "compare-amorph",
+    "print-ops",
+    "simulate",
+    "analyze",
+    "verify",
+    "combiner",
+    "energy",
+    "verbose",
+    "record-shuffle",
+];
+
+// Options every trace generating command takes, `run` reads the hardware from its config.
+const REPORT_OPTIONS: [&str; 7] = [
+    "output",
+    "format",
+    "print-ops",
+    "verbose",
+    "simulate",
+    "analyze",
+    "energy",
+];
+const HARDWARE_OPTIONS: [&str; 19] = [
+    "mapper-num",
+    "reducer-num",
+    "tile-sram-size",
+    "amorph-sram",
+    "alloc-policy",
+    "placement",
+    "mult-array",
+    "add-array",
+    "link-bw",
+    "dram-bw",
+    "hop-latency",
+    "noc",
+    "routing",
+    "dram-channels",
+    "dram-latency",
+    "dram-burst",
+    "dram-row-size",
+    "dram-row-miss",
+    "energy-cost",
+];
+const TILING_OPTIONS: [&str; 7] = [
+    "mapper-k",
+    "reducer-grid",
+    "auto-tiling",
+    "realloc",
+    "compare-amorph",
+    "verify",
+    "seed",
+];
+
+// Options of each command, None for unknown commands.
+fn known_options(command: &str) -> Option<Vec<&'static str>> {
+    let with = |base: &[&'static str], extra: &[&'static str]| [base, extra].concat();
+    let generate = with(&REPORT_OPTIONS, &HARDWARE_OPTIONS);
+    let tiled = with(&generate, &TILING_OPTIONS);
+    match command {
+        "outer-product" => Some(with(&tiled, &["gemm", "then", "chain", "record-shuffle"])),
+        "conv2d" => Some(with(&tiled, &["conv", "stride", "padding"])),
+        "word-count" => Some(with(
+            &generate,
+            &[
+                "input",
+                "mapper-buf-size",
+                "reducer-buf-size",
+                "overflow",
+                "mapper-spare",
+                "partitioner",
+                "combiner",
+                "counts",
+                "verify",
+            ],
+        )),
+        "dse" => Some(vec![
+            "mapper-num",
+            "reducer-num",
+            "tile-sram-size",
+            "amorph-sram",
+            "mult-array",
+            "add-array",
+            "gemm",
+            "sampling",
+            "seed",
+            "output",
+            "link-bw",
+            "dram-bw",
+        ]),
+        "run" => Some(with(
+            &REPORT_OPTIONS,
+            &["compare-amorph", "verify", "seed", "counts"],
+        )),
+        "simulate" => Some(vec![
+            "trace",
+            "analyze",
+            "energy",
+            "energy-cost",
+            "tile-sram-size",
+            "mult-array",
+            "add-array",
+            "link-bw",
+            "dram-bw",
+            "hop-latency",
+            "noc",
+            "routing",
+            "dram-channels",
+            "dram-latency",
+            "dram-burst",
+            "dram-row-size",
+            "dram-row-miss",
+        ]),
+        "validate" | "help" | "--help" | "-h" => Some(vec![]),
+        _ => None,
+    }
+}
+
+impl Args {
+    pub fn parse(args: &[String]) -> Result<Args, Box<dyn Error>> {
+        let command = args.first().ok_or("Missing command")?.clone();
+        let mut positional = vec![];
+        let mut options = HashMap::new();
+        let mut switches = HashSet::new();
+        let mut iter = args.iter().skip(1);
+        while let Some(arg) = iter.next() {
+            match arg.strip_prefix("--") {
+                Some(name) => {
+                    let (name, value) = match name.split_once('=') {
+                        Some((n, v)) => (n, Some(v.to_string())),
+                        None => (name, None),
+                    };
+                    // dse sweeps --amorph-sram over a list, e.g. `--amorph-sram false,true`.
+                    let list = command == "dse" && name == "amorph-sram";
+                    if SWITCHES.contains(&name) && !list {
+                        if value.is_some() {
+                            return Err(format!("Option --{} takes no value", name).into());
+                        }
+                        switches.insert(name.to_string());
+                        continue;
+                    }
+                    let value = match value {
+                        Some(v) => v,
+                        None => iter
+                            .next()
+                            .ok_or(format!("Option --{} needs a value", name))?
+                            .clone(),
+                    };
+                    options.insert(name.to_string(), value);
+                }
+                None => positional.push(arg.clone()),
+            }
+        }
+        if let Some(known) = known_options(&command) {
+            let unknown = options
+                .keys()
+                .chain(switches.iter())
+                .filter(|name| !known.contains(&name.as_str()))
+                .collect::<Vec<_>>();
+            if let Some(name) = unknown.into_iter().min() {
+                return Err(format!("Unknown option --{} for `{}`", name, command).into());
+            }
+            // Only run and validate take a path.
+            let paths = matches!(command.as_str(), "run" | "validate") as usize;
+            if let Some(arg) = positional.get(paths) {
+                return Err(format!("Unexpected argument `{}` for `{}`", arg, command).into());
+            }
+        }
+        Ok(Args {
+            command,
+            positional,
+            options,
+            switches,
+        })
+    }
+
+    pub fn flag(&self, name: &str) -> bool {
+        self.switches.contains(name)
+    }
+
+    pub fn get_str(&self, name: &str, default: &str) -> String {
+        self.options
+            .get(name)
+            .cloned()
+            .unwrap_or(default.to_string())
+    }
+
+    pub fn get<T: FromStr>(&self, name: &str, default: T) -> Result<T, Box<dyn Error>> {
+        match self.options.get(name) {
+            Some(v) => parse_value(name, v),
+            None => Ok(default),
+        }
+    }
+
+    // Counts and throughputs that divide.
+    pub fn get_positive(&self, name: &str, default: usize) -> Result<usize, Box<dyn Error>> {
+        match self.get(name, default)? {
+            0 => Err(format!("Option --{} must be positive", name).into()),
+            v => Ok(v),
+        }
+    }
+
+    pub fn get_list<T: FromStr>(
+        &self,
+        name: &str,
+        default: &str,
+    ) -> Result<Vec<T>, Box<dyn Error>> {
+        self.get_str(name, default)
+            .split(',')
+            .map(|v| parse_value(name, v))
+            .collect()
+    }
+}
+
+fn parse_value<T: FromStr>(name: &str, value: &str) -> Result<T, Box<dyn Error>> {
+    value
+        .trim()
+        .parse::<T>()
+        .map_err(|_| format!("Invalid value `{}` for --{}", value, name).into())
+}
+
+// "32x32" -> [32, 32].
+pub fn parse_pair(name: &str, value: &str) -> Result<[usize; 2], Box<dyn Error>> {
+    let (a, b) = value
+        .split_once('x')
+        .ok_or(format!("Option --{} expects MxN, got `{}`", name, value))?;
+    Ok([parse_value(name, a)?, parse_value(name, b)?])
+}
+
+// An MxN array, both dimensions positive.
+fn parse_array(name: &str, value: &str) -> Result<[usize; 2], Box<dyn Error>> {
+    let array = parse_pair(name, value)?;
+    if array.contains(&0) {
+        return Err(format!("Option --{} must be positive, got `{}`", name, value).into());
+    }
+    Ok(array)
+}
+
+// "128,128,512" -> [128, 128, 512].
+pub fn parse_gemm(value: &str) -> Result<[usize; 3], Box<dyn Error>> {
+    let dims = value
+        .split(',')
+        .map(|v| parse_value("gemm", v))
+        .collect::<Result<Vec<usize>, _>>()?;
+    <[usize; 3]>::try_from(dims)
+        .map_err(|_| format!("Option --gemm expects M,N,K, got `{}`", value).into())
+}
+
+// mac=0.8,dram_byte=15 -> {"mac": 0.8, "dram_byte": 15.0}.
+fn parse_costs(value: &str) -> Result<Value, Box<dyn Error>> {
+    let mut costs = serde_json::Map::new();
+    for entry in value.split(',') {
+        let (key, cost) = entry.split_once('=').ok_or(format!(
+            "Option --energy-cost expects KEY=PJ,..., got `{}`",
+            entry
+        ))?;
+        let cost = parse_value::<f64>("energy-cost", cost)?;
+        costs.insert(key.to_string(), cost.into());
+    }
+    Ok(Value::Object(costs))
+}
+
+fn parse_grid(value: &str) -> Result<ReducerGrid, Box<dyn Error>> {
+    match value {
+        "square" => Ok(ReducerGrid::Square),
+        "rows" => Ok(ReducerGrid::Rows),
+        "cols" => Ok(ReducerGrid::Cols),
+        _ => {
+            let [p, q] = parse_pair("reducer-grid", value)?;
+            Ok(ReducerGrid::Grid(p, q))
+        }
+    }
+}
+
+fn create_parent(path: &str) -> Result<(), Box<dyn Error>> {
+    if let Some(parent) = Path::new(path).parent() {
+        if !parent.as_os_str().is_empty() {
+            fs::create_dir_all(parent)?;
+        }
+    }
+    Ok(())
+}
+
+// With --energy every op carries its pJ and a json header the energy report.
+fn write_output(
+    args: &Args,
+    default_path: &str,
+    header: &TraceHeader,
+    op_list: &[Box<dyn OpTrait>],
+    hw: &HardwareConfig,
+    energy: Option<Value>,
+) -> Result<(), Box<dyn Error>> {
+    let path = args.get_str("output", default_path);
+    create_parent(&path)?;
+    match args.get_str("format", "json").as_str() {
+        "json" => {
+            let mut value = trace::dump_trace(header, op_list);
+            if let Some(energy) = energy {
+                value["header"]["energy"] = energy;
+                hw.energy.annotate(&mut value, op_list);
+            }
+            trace::save_json(&path, &value)?;
+        }
+        "json-v1" => trace::save_json(&path, &trace::dump_v1(op_list)?)?,
+        "text" => {
+            let lines = op_list
+                .iter()
+                .map(|op| match energy {
+                    Some(_) => {
+                        let pj = hw.energy.op_energy(op.as_ref()).total();
+                        format!("{} [{:.1} pJ]\n", op.format_op(), pj)
+                    }
+                    None => format!("{}\n", op.format_op()),
+                })
+                .collect::<String>();
+            fs::write(&path, lines)?;
+        }
+        f => return Err(format!("Unknown output format `{}`", f).into()),
+    }
+    println!("Wrote {} ops to {}", op_list.len(), path);
+    Ok(())
+}
+
+// The energy report for the trace under --energy.
+fn report(
+    args: &Args,
+    op_list: &[Box<dyn OpTrait>],
+    hw: &HardwareConfig,
+) -> Result<Option<Value>, Box<dyn Error>> {
+    if args.flag("print-ops") {
+        for op in op_list.iter() {
+            if args.flag("energy") {
+                let energy = hw.energy.op_energy(op.as_ref()).total();
+                println!("{} [{:.1} pJ]", op.format_op(), energy);
+            } else {
+                println!("{}", op.format_op());
+            }
+        }
+    }
+    if args.flag("simulate") || args.flag("energy") {
+        let sim = Simulator::new(hw.sim_config()).run(op_list)?;
+        if args.flag("simulate") {
+            sim.print_summary();
+        }
+        if args.flag("energy") {
+            let energy = hw.energy.report(op_list);
+            energy.print_summary(sim.total_cycles, hw.area());
+            return Ok(Some(energy.dump2json(
+                &hw.energy,
+                sim.total_cycles,
+                hw.area(),
+            )));
+        }
+    }
+    Ok(None)
+}
+
+fn hardware_from_args(
+    args: &Args,
+    mapper_num: usize,
+    reducer_num: usize,
+) -> Result<HardwareConfig, Box<dyn Error>> {
+    let dram_bw = args.get_positive("dram-bw", 32)?;
+    Ok(HardwareConfig {
+        mapper_num: args.get_positive("mapper-num", mapper_num)?,
+        reducer_num: args.get_positive("reducer-num", reducer_num)?,
+        tile_sram_size: args.get("tile-sram-size", 4096)?,
+        amorph_sram: args.flag("amorph-sram"),
+        mult_array: parse_array("mult-array", &args.get_str("mult-array", "32x32"))?,
+        add_array: parse_array("add-array", &args.get_str("add-array", "64x32"))?,
+        link_bw: args.get_positive("link-bw", 64)?,
+        dram_bw,
+        alloc_policy: AllocPolicy::from_name(&args.get_str("alloc-policy", "equal"))?,
+        placement: Placement::from_name(&args.get_str("placement", "sequential"))?,
+        hop_latency: args.get("hop-latency", 1)?,
+        noc: match args.options.get("noc") {
+            Some(t) => Some(NocTopology::from_name(t)?),
+            None => None,
+        },
+        routing: Routing::from_name(&args.get_str("routing", "xy"))?,
+        energy: match args.options.get("energy-cost") {
+            Some(costs) => EnergyModel::default().update(&parse_costs(costs)?)?,
+            None => EnergyModel::default(),
+        },
+        dram: match args.options.get("dram-channels") {
+            Some(_) => {
+                let default = DramConfig::new(args.get("dram-channels", 1)?, dram_bw);
+                let dram = DramConfig {
+                    latency: args.get("dram-latency", default.latency)?,
+                    burst: args.get("dram-burst", default.burst)?,
+                    row_size: args.get("dram-row-size", default.row_size)?,
+                    row_miss: args.get("dram-row-miss", default.row_miss)?,
+                    ..default
+                };
+                dram.validate()?;
+                Some(dram)
+            }
+            None => None,
+        },
+    })
+}
+
+fn schedule_from_args(args: &Args) -> Result<(Tiling, Realloc), Box<dyn Error>> {
+    let tiling = if args.flag("auto-tiling") {
+        Tiling::Auto
+    } else {
+        Tiling::Fixed {
+            mapper_k: args.get("mapper-k", 4)?,
+            grid: parse_grid(&args.get_str("reducer-grid", "square"))?,
+        }
+    };
+    let realloc = match args.get_str("realloc", "static").as_str() {
+        "static" => Realloc::Static,
+        _ => Realloc::Every(args.get("realloc", 1)?),
+    };
+    Ok((tiling, realloc))
+}
+
+fn gemm_from_args(args: &Args) -> Result<GemmConfig, Box<dyn Error>> {
+    let [m, n, k] = parse_gemm(&args.get_str("gemm", "128,128,512"))?;
+    let (tiling, realloc) = schedule_from_args(args)?;
+    Ok(GemmConfig {
+        m,
+        n,
+        k,
+        tiling,
+        realloc,
+        then: match args.options.get("then") {
+            Some(then) => then.split(';').map(parse_gemm).collect::<Result<_, _>>()?,
+            None => vec![],
+        },
+        chain: Chain::from_name(&args.get_str("chain", "memory"))?,
+    })
+}
+
+fn conv2d_from_args(args: &Args) -> Result<Conv2dConfig, Box<dyn Error>> {
+    let value = args.get_str("conv", "1,64,28,28,64,3,3");
+    let dims = value
+        .split(',')
+        .map(|v| parse_value("conv", v))
+        .collect::<Result<Vec<usize>, _>>()?;
+    let [n, c, h, w, k, r, s] = <[usize; 7]>::try_from(dims)
+        .map_err(|_| format!("Option --conv expects N,C,H,W,K,R,S, got `{}`", value))?;
+    let (tiling, realloc) = schedule_from_args(args)?;
+    Ok(Conv2dConfig {
+        conv: Conv2d {
+            n,
+            c,
+            h,
+            w,
+            k,
+            r,
+            s,
+            stride: args.get("stride", 1)?,
+            padding: args.get("padding", 0)?,
+        },
+        tiling,
+        realloc,
+    })
+}
+
+fn word_count_from_args(args: &Args) -> Result<WordCountConfig, Box<dyn Error>> {
+    let reducer_buf_size = args.get("reducer-buf-size", 64)?;
+    Ok(WordCountConfig {
+        input: args.get_str("input", "article/1.txt"),
+        mapper_buf_size: args.get("mapper-buf-size", 4)?,
+        reducer_buf_size,
+        partitioner: args.get_str("partitioner", "last-char"),
+        combiner: args.flag("combiner"),
+        overflow: Overflow::from_name(&args.get_str("overflow", "spill"))?,
+        mapper_spare: args.get("mapper-spare", reducer_buf_size)?,
+    })
+}
+
+fn outer_product_experiment(
+    args: &Args,
+    hw: &HardwareConfig,
+    gemm: &GemmConfig,
+    conv: Option<&Conv2d>,
+    default_path: &str,
+) -> Result<(), Box<dyn Error>> {
+    let config = hw.sim_config();
+    config.validate()?;
+    let mut outp = OuterProduct::from_config(hw, gemm)?;
+    if args.flag("verbose") {
+        outp.engine.verbose = true;
+        outp.print_schedule();
+    }
+    let mut header_conv = None;
+    if let Some(conv) = conv {
+        println!(
+            "Conv2d lowered to a {} x {} x {} GEMM, im2col expands {} input elements to {}",
+            gemm.m,
+            gemm.n,
+            gemm.k,
+            conv.input_len(),
+            gemm.m * gemm.k
+        );
+        header_conv = Some(conv.dump2json());
+    }
+    // A conv checks the lowered GEMM's C against a direct convolution of the same input.
+    let mut conv_reference = None;
+    if args.flag("verify") {
+        let seed = args.get("seed", 1)?;
+        match conv {
+            Some(conv) => {
+                let (input, weights) = conv.random_operands(seed);
+                let (a, b) = conv.im2col(&input, &weights)?;
+                outp.set_functional_operands(a, b);
+                conv_reference = Some(conv.reference(&input, &weights)?);
+            }
+            None => outp.set_functional(seed),
+        }
+    }
+    outp.exec_chain(&gemm.then, gemm.chain)?;
+    if args.flag("verify") {
+        outp.verify()?;
+        println!("Outer-product dataflow matches the reference GEMM");
+    }
+    if let Some(expected) = conv_reference {
+        let got = outp.functional_result().ok_or("Functional mode is off")?;
+        let mismatched = got
+            .iter()
+            .zip(expected.iter())
+            .filter(|(g, e)| g != e)
+            .count();
+        if mismatched > 0 || got.len() != expected.len() {
+            return Err(format!(
+                "Conv2d mismatch: {} of {} outputs differ from the direct convolution",
+                mismatched,
+                expected.len()
+            )
+            .into());
+        }
+        println!("Lowered GEMM matches the direct convolution");
+    }
+    let mut header = outp.trace_header();
+    if let Some(conv) = header_conv {
+        header.generator["workload"] = "conv2d".into();
+        header.generator["conv2d"] = conv;
+    }
+    let energy = report(args, outp.op_list(), hw)?;
+    write_output(args, default_path, &header, outp.op_list(), hw, energy)?;
+    if args.flag("analyze") || args.flag("compare-amorph") {
+        let cpa = CriticalPathAnalysis::analyze(outp.op_list(), &config)?;
+        cpa.print_summary();
+        let steps = cpa.step_breakdown(outp.op_list(), |op| outp.exec_step(op));
+        analysis::print_step_breakdown(&steps, &EXEC_STEPS);
+        if args.flag("compare-amorph") {
+            let mut other_hw = hw.clone();
+            other_hw.amorph_sram = !hw.amorph_sram;
+            let mut other = OuterProduct::from_config(&other_hw, gemm)?;
+            other.exec_chain(&gemm.then, gemm.chain)?;
+            let other_cpa = CriticalPathAnalysis::analyze(other.op_list(), &config)?;
+            println!(
+                "Critical path length: {} with amorph-sram {}, {} with amorph-sram {}",
+                cpa.makespan, hw.amorph_sram, other_cpa.makespan, other_hw.amorph_sram
+            );
+        }
+    }
+    Ok(())
+}
+
+fn word_count_experiment(
+    args: &Args,
+    hw: &HardwareConfig,
+    wc_config: &WordCountConfig,
+    default_path: &str,
+) -> Result<(), Box<dyn Error>> {
+    let mut wc = WordCount::from_config(hw, wc_config)?;
+    wc.set_verbose(args.flag("verbose"));
+    wc.run();
+    let counts = wc.scoreboard();
+    println!(
+        "Counted {} words, {} distinct",
+        counts.values().sum::<usize>(),
+        counts.len()
+    );
+    if args.options.contains_key("counts") {
+        let path = args.get_str("counts", "");
+        create_parent(&path)?;
+        let sorted = counts.iter().collect::<BTreeMap<_, _>>();
+        fs::write(&path, serde_json::to_string_pretty(&sorted)?)?;
+    }
+    wc.load_report().print_summary();
+    if args.flag("verify") {
+        wc.verify()?;
+        println!("Word count matches the sequential reference");
+    }
+    let energy = report(args, wc.op_list(), hw)?;
+    write_output(
+        args,
+        default_path,
+        &wc.trace_header(),
+        wc.op_list(),
+        hw,
+        energy,
+    )
+}
+
+// The GEMM through the generic MapReduce driver instead of the tile dataflow.
+fn gemm_job_experiment(
+    args: &Args,
+    hw: &HardwareConfig,
+    gemm: &GemmConfig,
+    default_path: &str,
+) -> Result<(), Box<dyn Error>> {
+    if !gemm.then.is_empty() || gemm.realloc != Realloc::Static || args.flag("compare-amorph") {
+        return Err("--record-shuffle takes no --then, --realloc or --compare-amorph".into());
+    }
+    let mut job = GemmMapReduce::from_config(hw, gemm, args.get("seed", 1)?)?;
+    job.engine.verbose = args.flag("verbose");
+    job.run();
+    job.load_report().print_summary();
+    if args.flag("verify") {
+        job.verify()?;
+        println!("Record shuffle matches the reference GEMM");
+    }
+    let energy = report(args, job.op_list(), hw)?;
+    write_output(
+        args,
+        default_path,
+        &job.trace_header(),
+        job.op_list(),
+        hw,
+        energy,
+    )
+}
+
+fn run_outer_product(args: &Args) -> Result<(), Box<dyn Error>> {
+    let hw = hardware_from_args(args, 8, 8)?;
+    let gemm = gemm_from_args(args)?;
+    if args.flag("record-shuffle") {
+        return gemm_job_experiment(args, &hw, &gemm, "result/outer-product.json");
+    }
+    outer_product_experiment(args, &hw, &gemm, None, "result/outer-product.json")
+}
+
+fn run_conv2d(args: &Args) -> Result<(), Box<dyn Error>> {
+    let conv = conv2d_from_args(args)?;
+    outer_product_experiment(
+        args,
+        &hardware_from_args(args, 8, 8)?,
+        &conv.gemm()?,
+        Some(&conv.conv),
+        "result/conv2d.json",
+    )
+}
+
+fn run_word_count(args: &Args) -> Result<(), Box<dyn Error>> {
+    word_count_experiment(
+        args,
+        &hardware_from_args(args, 4, 4)?,
+        &word_count_from_args(args)?,
+        "result/word-count.json",
+    )
+}
+
+// Options given on the command line still apply, e.g. --simulate or --output.
+fn run_experiment(args: &Args) -> Result<(), Box<dyn Error>> {
+    let path = args.positional.first().ok_or("Missing config path")?;
+    let exp = Experiment::load(path)?;
+    match &exp.workload {
+        WorkloadConfig::OuterProduct(gemm) => outer_product_experiment(
+            args,
+            &exp.hardware,
+            gemm,
+            None,
+            exp.output.as_deref().unwrap_or("result/outer-product.json"),
+        ),
+        WorkloadConfig::Conv2d(conv) => outer_product_experiment(
+            args,
+            &exp.hardware,
+            &conv.gemm()?,
+            Some(&conv.conv),
+            exp.output.as_deref().unwrap_or("result/conv2d.json"),
+        ),
+        WorkloadConfig::WordCount(wc) => word_count_experiment(
+            args,
+            &exp.hardware,
+            wc,
+            exp.output.as_deref().unwrap_or("result/word-count.json"),
+        ),
+    }
+}
+
+fn run_dse(args: &Args) -> Result<(), Box<dyn Error>> {
+    let pairs = |name: &str, default: &str| -> Result<Vec<[usize; 2]>, Box<dyn Error>> {
+        args.get_str(name, default)
+            .split(',')
+            .map(|v| parse_pair(name, v))
+            .collect()
+    };
+    let gemm = args
+        .get_str("gemm", "128,128,512")
+        .split(';')
+        .map(parse_gemm)
+        .collect::<Result<Vec<_>, _>>()?;
+    let space = DesignSpace {
+        mapper_num: args.get_list("mapper-num", "8")?,
+        reducer_num: args.get_list("reducer-num", "8")?,
+        tile_sram_size: args.get_list("tile-sram-size", "4096")?,
+        amorph_sram: args.get_list("amorph-sram", "false,true")?,
+        mult_array: pairs("mult-array", "32x32")?,
+        add_array: pairs("add-array", "64x32")?,
+        gemm,
+    };
+    let seed = args.get("seed", 1)?;
+    let sampling = match args.get_str("sampling", "grid").split_once(':') {
+        None if args.get_str("sampling", "grid") == "grid" => Sampling::Grid,
+        Some(("random", n)) => Sampling::Random {
+            samples: parse_value("sampling", n)?,
+            seed,
+        },
+        Some(("lhs", n)) => Sampling::LatinHypercube {
+            samples: parse_value("sampling", n)?,
+            seed,
+        },
+        _ => return Err("Option --sampling expects grid, random:N or lhs:N".into()),
+    };
+    let dse = Dse::new(
+        space,
+        sampling,
+        args.get_positive("link-bw", 64)?,
+        args.get_positive("dram-bw", 32)?,
+    );
+    let results = dse.run()?;
+    let path = args.get_str("output", "result/dse.csv");
+    create_parent(&path)?;
+    if path.ends_with(".json") {
+        dse::write_json(&path, &results)?;
+    } else {
+        dse::write_csv(&path, &results)?;
+    }
+    println!(
+        "Evaluated {} design points, {} on the Pareto frontier, wrote {}",
+        results.len(),
+        results.iter().filter(|r| r.pareto).count(),
+        path
+    );
+    Ok(())
+}
+
+fn run_validate(args: &Args) -> Result<(), Box<dyn Error>> {
+    let path = args.positional.first().ok_or("Missing trace path")?;
+    let errors = trace::validate_trace_file(path)?;
+    for e in errors.iter() {
+        println!("{}", e);
+    }
+    if !errors.is_empty() {
+        return Err(format!("{}: {} schema violations", path, errors.len()).into());
+    }
+    let trace = trace::load_trace(path)?;
+    println!(
+        "{}: valid, schema version {}, {} ops over {} modules",
+        path,
+        trace.header.schema_version,
+        trace.ops.len(),
+        trace.header.module_count
+    );
+    Ok(())
+}
+
+fn run_simulate(args: &Args) -> Result<(), Box<dyn Error>> {
+    let path = args.options.get("trace").ok_or("Missing --trace path")?;
+    let trace = trace::load_trace(path)?;
+    // A saved trace only knows its module count, so every module is a tile.
+    let mut hw = hardware_from_args(args, 1, 1)?;
+    hw.mapper_num = trace.header.module_count;
+    hw.reducer_num = 0;
+    let config = hw.sim_config();
+    let sim = Simulator::new(config.clone()).run(&trace.ops)?;
+    println!(
+        "Loaded {} ops over {} modules from {}",
+        trace.ops.len(),
+        trace.header.module_count,
+        path
+    );
+    sim.print_summary();
+    if args.flag("analyze") {
+        CriticalPathAnalysis::analyze(&trace.ops, &config)?.print_summary();
+    }
+    if args.flag("energy") {
+        hw.energy
+            .report(&trace.ops)
+            .print_summary(sim.total_cycles, hw.area());
+    }
+    Ok(())
+}
+
+pub fn run(args: &[String]) -> Result<(), Box<dyn Error>> {
+    let args = Args::parse(args)?;
+    match args.command.as_str() {
+        "outer-product" => run_outer_product(&args),
+        "conv2d" => run_conv2d(&args),
+        "word-count" => run_word_count(&args),
+        "dse" => run_dse(&args),
+        "run" => run_experiment(&args),
+        "validate" => run_validate(&args),
+        "simulate" => run_simulate(&args),
+        "help" | "--help" | "-h" => {
+            println!("{}", USAGE);
+            Ok(())
+        }
+        c => Err(format!("Unknown command `{}`", c).into()),
+    }
+}
@@ -0,0 +1,399 @@
+use serde_json::Value;
+use std::error::Error;
+use std::fs::File;
+use std::io::BufReader;
+
+use crate::conv::Conv2d;
+use crate::dram::DramConfig;
+use crate::energy::EnergyModel;
+use crate::mapreduce::{AllocPolicy, Overflow};
+use crate::noc::{NocConfig, NocTopology, Routing};
+use crate::outer_product::{Chain, Realloc, ReducerGrid, Tiling};
+use crate::simulator::SimConfig;
+use crate::topology::{Mesh, Placement};
+use crate::util::{bool_field, check_keys, field, pair_field, str_field, usize_array, usize_field};
+
+// Experiment file layout, unknown or malformed fields are an error:
+// {
+//   "hardware": {
+//     "mapper_num": 8, "reducer_num": 8, "tile_sram_size": 4096,
+//     "mult_array": [32, 32], "add_array": [64, 32],
+//     "amorph_sram": false, "link_bw": 64, "dram_bw": 32,
+//     "alloc_policy": "equal" | "demand-capped" | "proportional" | "min-spill",
+//     "placement": "sequential" | "nearest", "hop_latency": 1,
+//     "noc": "mesh" | "torus" | "crossbar", "routing": "xy" | "adaptive",
+//     "dram": { "channels": 4, "latency": 40, "burst": 16, "row_size": 1024, "row_miss": 20 },
+//     "energy": { "mac": 1.0, "add": 0.1, "sram_local": 2.5, "sram_remote": 5.0, "hop": 1.0,
+//                 "dram_byte": 20.0, "reconfig": 50.0, "element_bytes": 2.0, "mac_area": 400.0,
+//                 "add_area": 60.0, "sram_area": 2.0, "router_area": 20000.0 }
+//   },
+//   "workload": { "type": "outer-product", "gemm": [128, 128, 512],
+//                 "tiling": "auto" | { "mapper_k": 4, "reducer_grid": "square" | "rows" | "cols" | [2, 4] },
+//                 "realloc": "static" | <re-allocate every n k-iterations>,
+//                 "then": [[128, 64, 128]], "chain": "batch" | "memory" | "on-chip" }
+//            or { "type": "conv2d", "tiling", "realloc" as above,
+//                 "conv": { "n": 1, "c": 64, "h": 28, "w": 28, "k": 64, "r": 3, "s": 3,
+//                           "stride": 1, "padding": 0 } }
+//            or { "type": "word-count", "input": "article/1.txt",
+//                 "mapper_buf_size": 4, "reducer_buf_size": 64,
+//                 "partitioner": "last-char" | "hash" | "range" | "first-char",
+//                 "combiner": false, "overflow": "spill" | "stall", "mapper_spare": 64 },
+//   "output": "result/outer-product-no-amorsram.json"
+// }
+// Hardware: the counts, tile_sram_size and arrays are required, the counts, arrays and
+// bandwidths must be positive. noc routes transfers over links of link_bw with hop_latency
+// per router, without it transfers only pay hops. dram splits memory into channels of
+// dram_bw each, only channels is required, without it memory is one dram_bw pipe. energy
+// holds pJ per event and um^2 per unit, any subset overrides the defaults above.
+// Workloads: gemm and conv are required, tiling defaults to mapper_k 4 on a square grid,
+// realloc to static, chain to memory, stride to 1 and padding to 0. Word-count needs input
+// and both buffer sizes, reducer_buf_size and mapper_spare count records and mapper_spare,
+// the sram each mapper lends under amorph_sram, defaults to reducer_buf_size.
+
+#[derive(Debug, Clone)]
+pub struct HardwareConfig {
+    pub mapper_num: usize,
+    pub reducer_num: usize,
+    pub tile_sram_size: usize,
+    pub amorph_sram: bool,
+    pub mult_array: [usize; 2],
+    pub add_array: [usize; 2],
+    pub link_bw: usize,
+    pub dram_bw: usize,
+    pub alloc_policy: AllocPolicy,
+    pub placement: Placement,
+    pub hop_latency: usize,
+    pub noc: Option<NocTopology>,
+    pub routing: Routing,
+    pub dram: Option<DramConfig>,
+    pub energy: EnergyModel,
+}
+
+impl HardwareConfig {
+    pub fn from_json(value: &Value) -> Result<HardwareConfig, Box<dyn Error>> {
+        check_keys(
+            value,
+            &[
+                "mapper_num",
+                "reducer_num",
+                "tile_sram_size",
+                "amorph_sram",
+                "mult_array",
+                "add_array",
+                "link_bw",
+                "dram_bw",
+                "alloc_policy",
+                "placement",
+                "hop_latency",
+                "noc",
+                "routing",
+                "dram",
+                "energy",
+            ],
+            "hardware",
+        )?;
+        let optional = |key: &str, default: usize| match value.get(key) {
+            Some(_) => usize_field(value, key),
+            None => Ok(default),
+        };
+        let hw = HardwareConfig {
+            mapper_num: usize_field(value, "mapper_num")?,
+            reducer_num: usize_field(value, "reducer_num")?,
+            tile_sram_size: usize_field(value, "tile_sram_size")?,
+            amorph_sram: match value.get("amorph_sram") {
+                Some(_) => bool_field(value, "amorph_sram")?,
+                None => false,
+            },
+            mult_array: pair_field(value, "mult_array")?,
+            add_array: pair_field(value, "add_array")?,
+            link_bw: optional("link_bw", 64)?,
+            dram_bw: optional("dram_bw", 32)?,
+            alloc_policy: match value.get("alloc_policy") {
+                Some(_) => AllocPolicy::from_name(str_field(value, "alloc_policy")?)?,
+                None => AllocPolicy::Equal,
+            },
+            placement: match value.get("placement") {
+                Some(_) => Placement::from_name(str_field(value, "placement")?)?,
+                None => Placement::Sequential,
+            },
+            hop_latency: optional("hop_latency", 1)?,
+            noc: match value.get("noc") {
+                Some(_) => Some(NocTopology::from_name(str_field(value, "noc")?)?),
+                None => None,
+            },
+            routing: match value.get("routing") {
+                Some(_) => Routing::from_name(str_field(value, "routing")?)?,
+                None => Routing::Xy,
+            },
+            dram: match value.get("dram") {
+                Some(dram) => Some(DramConfig::from_json(dram, optional("dram_bw", 32)?)?),
+                None => None,
+            },
+            energy: match value.get("energy") {
+                Some(energy) => EnergyModel::from_json(energy)?,
+                None => EnergyModel::default(),
+            },
+        };
+        hw.validate()?;
+        Ok(hw)
+    }
+
+    // Counts and throughputs the simulator divides by.
+    pub fn validate(&self) -> Result<(), Box<dyn Error>> {
+        let positive = [
+            ("mapper_num", self.mapper_num > 0),
+            ("reducer_num", self.reducer_num > 0),
+            ("mult_array", !self.mult_array.contains(&0)),
+            ("add_array", !self.add_array.contains(&0)),
+            ("link_bw", self.link_bw > 0),
+            ("dram_bw", self.dram_bw > 0),
+        ];
+        match positive.iter().find(|(_, ok)| !ok) {
+            Some((key, _)) => Err(format!("Field `{}` must be positive", key).into()),
+            None => Ok(()),
+        }
+    }
+
+    pub fn tile_num(&self) -> usize {
+        self.mapper_num + self.reducer_num
+    }
+
+    // um^2.
+    pub fn area(&self) -> f64 {
+        self.energy.area(
+            self.tile_num(),
+            self.mult_array,
+            self.add_array,
+            self.tile_sram_size,
+        )
+    }
+
+    pub fn sim_config(&self) -> SimConfig {
+        let mut config =
+            SimConfig::new(self.mult_array, self.add_array, self.link_bw, self.dram_bw);
+        config.hop_latency = self.hop_latency;
+        config.noc = self.noc.map(|topology| {
+            NocConfig::new(
+                topology,
+                self.routing,
+                Mesh::fit(self.tile_num()),
+                self.link_bw,
+                self.hop_latency,
+            )
+        });
+        config.dram = self.dram.clone();
+        config
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct GemmConfig {
+    pub m: usize,
+    pub n: usize,
+    pub k: usize,
+    pub tiling: Tiling,
+    pub realloc: Realloc,
+    // GEMMs run after the first, in the same trace.
+    pub then: Vec<[usize; 3]>,
+    pub chain: Chain,
+}
+
+impl GemmConfig {
+    fn from_json(value: &Value) -> Result<GemmConfig, Box<dyn Error>> {
+        check_keys(
+            value,
+            &["type", "gemm", "tiling", "realloc", "then", "chain"],
+            "outer-product workload",
+        )?;
+        let [m, n, k] = usize_array(field(value, "gemm")?, "gemm")?;
+        let then = match value.get("then") {
+            Some(then) => then
+                .as_array()
+                .ok_or("Field `then` should be a list of [m, n, k]")?
+                .iter()
+                .map(|dims| usize_array(dims, "then"))
+                .collect::<Result<Vec<_>, _>>()?,
+            None => vec![],
+        };
+        let (tiling, realloc) = schedule_from_json(value)?;
+        Ok(GemmConfig {
+            m,
+            n,
+            k,
+            tiling,
+            realloc,
+            then,
+            chain: match value.get("chain") {
+                Some(_) => Chain::from_name(str_field(value, "chain")?)?,
+                None => Chain::Memory,
+            },
+        })
+    }
+}
+
+// Optional "tiling" and "realloc" of a workload on the outer-product dataflow.
+fn schedule_from_json(value: &Value) -> Result<(Tiling, Realloc), Box<dyn Error>> {
+    let tiling = match value.get("tiling") {
+        None => Tiling::default(),
+        Some(Value::String(s)) if s == "auto" => Tiling::Auto,
+        Some(tiling) => {
+            check_keys(tiling, &["mapper_k", "reducer_grid"], "tiling")?;
+            Tiling::Fixed {
+                mapper_k: usize_field(tiling, "mapper_k")?,
+                grid: match field(tiling, "reducer_grid")? {
+                    Value::String(s) if s == "square" => ReducerGrid::Square,
+                    Value::String(s) if s == "rows" => ReducerGrid::Rows,
+                    Value::String(s) if s == "cols" => ReducerGrid::Cols,
+                    _ => {
+                        let [p, q] = pair_field(tiling, "reducer_grid")?;
+                        ReducerGrid::Grid(p, q)
+                    }
+                },
+            }
+        }
+    };
+    let realloc = match value.get("realloc") {
+        None => Realloc::Static,
+        Some(Value::String(s)) if s == "static" => Realloc::Static,
+        Some(_) => Realloc::Every(usize_field(value, "realloc")?),
+    };
+    Ok((tiling, realloc))
+}
+
+#[derive(Debug, Clone)]
+pub struct Conv2dConfig {
+    pub conv: Conv2d,
+    pub tiling: Tiling,
+    pub realloc: Realloc,
+}
+
+impl Conv2dConfig {
+    fn from_json(value: &Value) -> Result<Conv2dConfig, Box<dyn Error>> {
+        check_keys(
+            value,
+            &["type", "conv", "tiling", "realloc"],
+            "conv2d workload",
+        )?;
+        let (tiling, realloc) = schedule_from_json(value)?;
+        Ok(Conv2dConfig {
+            conv: Conv2d::from_json(field(value, "conv")?)?,
+            tiling,
+            realloc,
+        })
+    }
+
+    // The im2col GEMM run on the outer-product dataflow.
+    pub fn gemm(&self) -> Result<GemmConfig, Box<dyn Error>> {
+        let [m, n, k] = self.conv.lower()?;
+        Ok(GemmConfig {
+            m,
+            n,
+            k,
+            tiling: self.tiling,
+            realloc: self.realloc,
+            then: vec![],
+            chain: Chain::Batch,
+        })
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct WordCountConfig {
+    pub input: String,
+    pub mapper_buf_size: usize,
+    pub reducer_buf_size: usize,
+    pub partitioner: String,
+    pub combiner: bool,
+    pub overflow: Overflow,
+    pub mapper_spare: usize,
+}
+
+impl WordCountConfig {
+    fn from_json(value: &Value) -> Result<WordCountConfig, Box<dyn Error>> {
+        check_keys(
+            value,
+            &[
+                "type",
+                "input",
+                "mapper_buf_size",
+                "reducer_buf_size",
+                "partitioner",
+                "combiner",
+                "overflow",
+                "mapper_spare",
+            ],
+            "word-count workload",
+        )?;
+        let reducer_buf_size = usize_field(value, "reducer_buf_size")?;
+        Ok(WordCountConfig {
+            input: str_field(value, "input")?.to_string(),
+            mapper_buf_size: usize_field(value, "mapper_buf_size")?,
+            reducer_buf_size,
+            partitioner: match value.get("partitioner") {
+                Some(_) => str_field(value, "partitioner")?.to_string(),
+                None => "last-char".to_string(),
+            },
+            combiner: match value.get("combiner") {
+                Some(_) => bool_field(value, "combiner")?,
+                None => false,
+            },
+            overflow: match value.get("overflow") {
+                Some(_) => Overflow::from_name(str_field(value, "overflow")?)?,
+                None => Overflow::Spill,
+            },
+            mapper_spare: match value.get("mapper_spare") {
+                Some(_) => usize_field(value, "mapper_spare")?,
+                None => reducer_buf_size,
+            },
+        })
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum WorkloadConfig {
+    OuterProduct(GemmConfig),
+    WordCount(WordCountConfig),
+    Conv2d(Conv2dConfig),
+}
+
+impl WorkloadConfig {
+    pub fn from_json(value: &Value) -> Result<WorkloadConfig, Box<dyn Error>> {
+        match str_field(value, "type")? {
+            "outer-product" => Ok(WorkloadConfig::OuterProduct(GemmConfig::from_json(value)?)),
+            "word-count" => Ok(WorkloadConfig::WordCount(WordCountConfig::from_json(
+                value,
+            )?)),
+            "conv2d" => Ok(WorkloadConfig::Conv2d(Conv2dConfig::from_json(value)?)),
+            t => Err(format!("Unknown workload type `{}`", t).into()),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Experiment {
+    pub hardware: HardwareConfig,
+    pub workload: WorkloadConfig,
+    pub output: Option<String>,
+}
+
+impl Experiment {
+    pub fn from_json(value: &Value) -> Result<Experiment, Box<dyn Error>> {
+        check_keys(value, &["hardware", "workload", "output"], "experiment")?;
+        let output = match value.get("output") {
+            Some(_) => Some(str_field(value, "output")?.to_string()),
+            None => None,
+        };
+        Ok(Experiment {
+            hardware: HardwareConfig::from_json(field(value, "hardware")?)?,
+            workload: WorkloadConfig::from_json(field(value, "workload")?)?,
+            output,
+        })
+    }
+
+    pub fn load(path: &str) -> Result<Experiment, Box<dyn Error>> {
+        let file = File::open(path)?;
+        let value: Value = serde_json::from_reader(BufReader::new(file))
+            .map_err(|e| format!("{}: {}", path, e))?;
+        Experiment::from_json(&value).map_err(|e| format!("{}: {}", path, e).into())
+    }
+}
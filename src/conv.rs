@@ -0,0 +1,171 @@
+use serde_json::{json, Value};
+use std::error::Error;
+
+use crate::functional::random_matrix;
+use crate::util::{check_keys, usize_field, XorShift};
+
+// NCHW input convolved with KCRS weights into an N x K x P x Q output.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Conv2d {
+    pub n: usize,
+    pub c: usize,
+    pub h: usize,
+    pub w: usize,
+    pub k: usize,
+    pub r: usize,
+    pub s: usize,
+    pub stride: usize,
+    pub padding: usize,
+}
+
+impl Conv2d {
+    // {"n", "c", "h", "w", "k", "r", "s", "stride": 1, "padding": 0}.
+    pub fn from_json(value: &Value) -> Result<Conv2d, Box<dyn Error>> {
+        check_keys(
+            value,
+            &["n", "c", "h", "w", "k", "r", "s", "stride", "padding"],
+            "conv",
+        )?;
+        let optional = |key: &str, default: usize| match value.get(key) {
+            Some(_) => usize_field(value, key),
+            None => Ok(default),
+        };
+        Ok(Conv2d {
+            n: usize_field(value, "n")?,
+            c: usize_field(value, "c")?,
+            h: usize_field(value, "h")?,
+            w: usize_field(value, "w")?,
+            k: usize_field(value, "k")?,
+            r: usize_field(value, "r")?,
+            s: usize_field(value, "s")?,
+            stride: optional("stride", 1)?,
+            padding: optional("padding", 0)?,
+        })
+    }
+
+    pub fn dump2json(&self) -> Value {
+        json!({
+            "n": self.n,
+            "c": self.c,
+            "h": self.h,
+            "w": self.w,
+            "k": self.k,
+            "r": self.r,
+            "s": self.s,
+            "stride": self.stride,
+            "padding": self.padding,
+        })
+    }
+
+    // Output height and width.
+    pub fn output_size(&self) -> Result<(usize, usize), Box<dyn Error>> {
+        if self.stride == 0 {
+            return Err("Conv2d stride should be positive".into());
+        }
+        let (h, w) = (self.h + 2 * self.padding, self.w + 2 * self.padding);
+        if self.r == 0 || self.s == 0 || self.r > h || self.s > w {
+            return Err(format!(
+                "Conv2d kernel {} x {} does not fit the padded {} x {} input",
+                self.r, self.s, h, w
+            )
+            .into());
+        }
+        Ok((
+            (h - self.r) / self.stride + 1,
+            (w - self.s) / self.stride + 1,
+        ))
+    }
+
+    // im2col: every output pixel is a row of C * R * S input patch elements, the
+    // weights are a C * R * S x K matrix, so the output is an N * P * Q x K GEMM.
+    pub fn lower(&self) -> Result<[usize; 3], Box<dyn Error>> {
+        let (p, q) = self.output_size()?;
+        Ok([self.n * p * q, self.k, self.c * self.r * self.s])
+    }
+
+    pub fn input_len(&self) -> usize {
+        self.n * self.c * self.h * self.w
+    }
+
+    pub fn weight_len(&self) -> usize {
+        self.k * self.c * self.r * self.s
+    }
+
+    // Input element the patch row of output pixel [b, y, x] takes at column [ch, i, j],
+    // None inside the padding.
+    fn patch(&self, input: &[i64], pixel: [usize; 3], tap: [usize; 3]) -> Option<i64> {
+        let ([b, y, x], [ch, i, j]) = (pixel, tap);
+        let row = (y * self.stride + i).checked_sub(self.padding)?;
+        let col = (x * self.stride + j).checked_sub(self.padding)?;
+        if row >= self.h || col >= self.w {
+            return None;
+        }
+        Some(input[((b * self.c + ch) * self.h + row) * self.w + col])
+    }
+
+    // Lowered A (N * P * Q x C * R * S) and B (C * R * S x K), row-major.
+    pub fn im2col(
+        &self,
+        input: &[i64],
+        weights: &[i64],
+    ) -> Result<(Vec<i64>, Vec<i64>), Box<dyn Error>> {
+        let (p, q) = self.output_size()?;
+        let mut a = Vec::with_capacity(self.n * p * q * self.c * self.r * self.s);
+        for b in 0..self.n {
+            for y in 0..p {
+                for x in 0..q {
+                    for ch in 0..self.c {
+                        for i in 0..self.r {
+                            for j in 0..self.s {
+                                a.push(self.patch(input, [b, y, x], [ch, i, j]).unwrap_or(0));
+                            }
+                        }
+                    }
+                }
+            }
+        }
+        let crs = self.c * self.r * self.s;
+        let mut w = vec![0; crs * self.k];
+        for kk in 0..self.k {
+            for e in 0..crs {
+                w[e * self.k + kk] = weights[kk * crs + e];
+            }
+        }
+        Ok((a, w))
+    }
+
+    // Direct convolution, laid out like the lowered GEMM output: row (b, y, x), column k.
+    pub fn reference(&self, input: &[i64], weights: &[i64]) -> Result<Vec<i64>, Box<dyn Error>> {
+        let (p, q) = self.output_size()?;
+        let mut out = vec![0; self.n * p * q * self.k];
+        for b in 0..self.n {
+            for y in 0..p {
+                for x in 0..q {
+                    for kk in 0..self.k {
+                        let mut sum = 0;
+                        for ch in 0..self.c {
+                            for i in 0..self.r {
+                                for j in 0..self.s {
+                                    let weight =
+                                        weights[((kk * self.c + ch) * self.r + i) * self.s + j];
+                                    sum += self.patch(input, [b, y, x], [ch, i, j]).unwrap_or(0)
+                                        * weight;
+                                }
+                            }
+                        }
+                        out[((b * p + y) * q + x) * self.k + kk] = sum;
+                    }
+                }
+            }
+        }
+        Ok(out)
+    }
+
+    // Random input and weights for a functional run.
+    pub fn random_operands(&self, seed: u64) -> (Vec<i64>, Vec<i64>) {
+        let mut rng = XorShift::new(seed);
+        let input = random_matrix(self.input_len(), &mut rng);
+        let weights = random_matrix(self.weight_len(), &mut rng);
+        (input, weights)
+    }
+}
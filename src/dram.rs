@@ -0,0 +1,85 @@
+use serde_json::Value;
+use std::error::Error;
+
+use crate::util::{check_keys, usize_field};
+
+// Off-chip memory behind module -1. Every tile's data lives on channel
+// pid % channels and each channel keeps one row open.
+#[derive(Debug, Clone)]
+pub struct DramConfig {
+    pub channels: usize,
+    // Elements per cycle of one channel.
+    pub channel_bw: usize,
+    // Cycles from request to first data.
+    pub latency: usize,
+    // Transfers move whole bursts of this many elements.
+    pub burst: usize,
+    // Elements per row.
+    pub row_size: usize,
+    // Cycles to close the open row and activate another.
+    pub row_miss: usize,
+}
+
+impl DramConfig {
+    pub fn new(channels: usize, channel_bw: usize) -> DramConfig {
+        DramConfig {
+            channels,
+            channel_bw,
+            latency: 40,
+            burst: 16,
+            row_size: 1024,
+            row_miss: 20,
+        }
+    }
+
+    // {"channels": 4, "latency": 40, "burst": 16, "row_size": 1024, "row_miss": 20},
+    // channels is required and channel_bw is the hardware dram_bw.
+    pub fn from_json(value: &Value, channel_bw: usize) -> Result<DramConfig, Box<dyn Error>> {
+        check_keys(
+            value,
+            &["channels", "latency", "burst", "row_size", "row_miss"],
+            "dram",
+        )?;
+        let default = DramConfig::new(usize_field(value, "channels")?, channel_bw);
+        let optional = |key: &str, default: usize| match value.get(key) {
+            Some(_) => usize_field(value, key),
+            None => Ok(default),
+        };
+        let config = DramConfig {
+            latency: optional("latency", default.latency)?,
+            burst: optional("burst", default.burst)?,
+            row_size: optional("row_size", default.row_size)?,
+            row_miss: optional("row_miss", default.row_miss)?,
+            ..default
+        };
+        config.validate()?;
+        Ok(config)
+    }
+
+    pub fn validate(&self) -> Result<(), Box<dyn Error>> {
+        if self.channels == 0 || self.channel_bw == 0 || self.burst == 0 || self.row_size == 0 {
+            return Err("dram channels, dram_bw, burst and row_size must be positive".into());
+        }
+        Ok(())
+    }
+
+    pub fn channel(&self, pid: usize) -> usize {
+        pid % self.channels
+    }
+
+    // Rows a transfer of length elements touches.
+    pub fn rows(&self, length: usize) -> usize {
+        length.div_ceil(self.row_size).max(1)
+    }
+
+    // Rows a transfer of length elements activates, the first one is free
+    // when it is already open.
+    pub fn row_misses(&self, length: usize, row_open: bool) -> usize {
+        self.rows(length) - row_open as usize
+    }
+
+    pub fn latency(&self, length: usize, row_misses: usize) -> usize {
+        let bursts = length.div_ceil(self.burst);
+        self.latency + row_misses * self.row_miss + (bursts * self.burst).div_ceil(self.channel_bw)
+    }
+}
@@ -0,0 +1,307 @@
+use serde_json::{json, Value};
+use std::error::Error;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+
+use crate::op::OpType;
+use crate::outer_product::OuterProduct;
+use crate::simulator::{SimConfig, Simulator, MEMORY_ID};
+use crate::util::XorShift;
+
+#[derive(Debug, Clone)]
+pub struct DesignPoint {
+    pub mapper_num: usize,
+    pub reducer_num: usize,
+    pub tile_sram_size: usize,
+    pub amorph_sram: bool,
+    pub mult_array: [usize; 2],
+    pub add_array: [usize; 2],
+    // GEMM m, n, k.
+    pub gemm: [usize; 3],
+}
+
+impl DesignPoint {
+    // Shapes `OuterProduct::set_gemm` rejects are skipped by `Dse::run`.
+    pub fn is_valid(&self) -> bool {
+        self.mapper_num > 0
+            && self.reducer_num > 0
+            && self.gemm.iter().all(|d| *d > 0)
+            && self
+                .mult_array
+                .iter()
+                .chain(self.add_array.iter())
+                .all(|d| *d > 0)
+    }
+
+    pub fn dump2json(&self) -> Value {
+        json!({
+            "mapper_num": self.mapper_num,
+            "reducer_num": self.reducer_num,
+            "tile_sram_size": self.tile_sram_size,
+            "amorph_sram": self.amorph_sram,
+            "mult_array": self.mult_array,
+            "add_array": self.add_array,
+            "gemm": self.gemm,
+        })
+    }
+}
+
+// Candidate values of every parameter.
+#[derive(Debug, Clone)]
+pub struct DesignSpace {
+    pub mapper_num: Vec<usize>,
+    pub reducer_num: Vec<usize>,
+    pub tile_sram_size: Vec<usize>,
+    pub amorph_sram: Vec<bool>,
+    pub mult_array: Vec<[usize; 2]>,
+    pub add_array: Vec<[usize; 2]>,
+    pub gemm: Vec<[usize; 3]>,
+}
+
+impl DesignSpace {
+    fn dims(&self) -> [usize; 7] {
+        [
+            self.mapper_num.len(),
+            self.reducer_num.len(),
+            self.tile_sram_size.len(),
+            self.amorph_sram.len(),
+            self.mult_array.len(),
+            self.add_array.len(),
+            self.gemm.len(),
+        ]
+    }
+
+    fn point(&self, idx: [usize; 7]) -> DesignPoint {
+        DesignPoint {
+            mapper_num: self.mapper_num[idx[0]],
+            reducer_num: self.reducer_num[idx[1]],
+            tile_sram_size: self.tile_sram_size[idx[2]],
+            amorph_sram: self.amorph_sram[idx[3]],
+            mult_array: self.mult_array[idx[4]],
+            add_array: self.add_array[idx[5]],
+            gemm: self.gemm[idx[6]],
+        }
+    }
+
+    pub fn size(&self) -> usize {
+        self.dims().iter().product()
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum Sampling {
+    Grid,
+    Random { samples: usize, seed: u64 },
+    LatinHypercube { samples: usize, seed: u64 },
+}
+
+#[derive(Debug, Clone)]
+pub struct DseResult {
+    pub point: DesignPoint,
+    pub latency: usize,
+    // Total on-chip sram of all tiles.
+    pub sram: usize,
+    // Elements moved from/to memory.
+    pub offchip: usize,
+    pub pareto: bool,
+}
+
+impl DseResult {
+    fn dominates(&self, other: &DseResult) -> bool {
+        let le = self.latency <= other.latency
+            && self.sram <= other.sram
+            && self.offchip <= other.offchip;
+        let lt =
+            self.latency < other.latency || self.sram < other.sram || self.offchip < other.offchip;
+        le && lt
+    }
+
+    pub fn dump2json(&self) -> Value {
+        json!({
+            "point": self.point.dump2json(),
+            "latency": self.latency,
+            "sram": self.sram,
+            "offchip": self.offchip,
+            "pareto": self.pareto,
+        })
+    }
+}
+
+pub struct Dse {
+    space: DesignSpace,
+    sampling: Sampling,
+    link_bw: usize,
+    dram_bw: usize,
+}
+
+impl Dse {
+    pub fn new(space: DesignSpace, sampling: Sampling, link_bw: usize, dram_bw: usize) -> Dse {
+        Dse {
+            space,
+            sampling,
+            link_bw,
+            dram_bw,
+        }
+    }
+
+    pub fn points(&self) -> Vec<DesignPoint> {
+        let dims = self.space.dims();
+        if dims.contains(&0) {
+            return vec![];
+        }
+        let indices: Vec<[usize; 7]> = match self.sampling {
+            Sampling::Grid => (0..self.space.size())
+                .map(|mut flat| {
+                    let mut idx = [0; 7];
+                    for (d, len) in dims.iter().enumerate() {
+                        idx[d] = flat % len;
+                        flat /= len;
+                    }
+                    idx
+                })
+                .collect(),
+            Sampling::Random { samples, seed } => {
+                let mut rng = XorShift::new(seed);
+                (0..samples)
+                    .map(|_| {
+                        let mut idx = [0; 7];
+                        for (d, len) in dims.iter().enumerate() {
+                            idx[d] = rng.below(*len);
+                        }
+                        idx
+                    })
+                    .collect()
+            }
+            // Each dim is cut into `samples` strata, every stratum is used once.
+            Sampling::LatinHypercube { samples, seed } => {
+                let mut rng = XorShift::new(seed);
+                let mut idx = vec![[0; 7]; samples];
+                for (d, len) in dims.iter().enumerate() {
+                    let mut strata = (0..samples).collect::<Vec<_>>();
+                    rng.shuffle(&mut strata);
+                    for (s, stratum) in strata.iter().enumerate() {
+                        let u = (*stratum as f64 + rng.next_f64()) / samples as f64;
+                        idx[s][d] = ((u * *len as f64) as usize).min(len - 1);
+                    }
+                }
+                idx
+            }
+        };
+        indices
+            .into_iter()
+            .map(|idx| self.space.point(idx))
+            .collect()
+    }
+
+    // Err when the GEMM does not tile onto the point's mappers and reducers or the
+    // trace does not simulate.
+    pub fn evaluate(&self, point: &DesignPoint) -> Result<DseResult, Box<dyn Error>> {
+        let mut outp = OuterProduct::new(
+            point.mapper_num,
+            point.reducer_num,
+            point.tile_sram_size,
+            point.amorph_sram,
+            point.mult_array,
+            point.add_array,
+        );
+        let [m, n, k] = point.gemm;
+        outp.set_gemm(m, n, k)?;
+        outp.exec();
+        let sim = Simulator::new(SimConfig::new(
+            point.mult_array,
+            point.add_array,
+            self.link_bw,
+            self.dram_bw,
+        ));
+        let report = sim.run(outp.op_list())?;
+        let offchip = outp
+            .op_list()
+            .iter()
+            .filter(|op| op.op_type() == OpType::TransOp && op.modules().contains(&MEMORY_ID))
+            .map(|op| op.complexity())
+            .sum();
+        Ok(DseResult {
+            point: point.clone(),
+            latency: report.total_cycles,
+            sram: point.tile_sram_size * (point.mapper_num + point.reducer_num),
+            offchip,
+            pareto: false,
+        })
+    }
+
+    pub fn run(&self) -> Result<Vec<DseResult>, Box<dyn Error>> {
+        // Shared by every point, so no point could simulate.
+        if self.link_bw == 0 || self.dram_bw == 0 {
+            return Err("link_bw and dram_bw must be positive".into());
+        }
+        let mut results = vec![];
+        for point in self.points() {
+            if !point.is_valid() {
+                println!("Skip invalid design point {:?}", point);
+                continue;
+            }
+            match self.evaluate(&point) {
+                Ok(result) => results.push(result),
+                Err(e) => println!("Skip design point {:?}: {}", point, e),
+            }
+        }
+        mark_pareto(&mut results);
+        Ok(results)
+    }
+}
+
+// Pareto frontier of latency vs. sram vs. off-chip traffic, all minimised.
+pub fn mark_pareto(results: &mut [DseResult]) {
+    for i in 0..results.len() {
+        results[i].pareto = !results.iter().any(|r| r.dominates(&results[i]));
+    }
+}
+
+pub fn write_csv(path: &str, results: &[DseResult]) -> Result<(), Box<dyn Error>> {
+    let mut writer = BufWriter::new(File::create(path)?);
+    writeln!(
+        writer,
+        "mapper_num,reducer_num,tile_sram_size,amorph_sram,mult_m,mult_n,add_m,add_n,m,n,k,latency,sram,offchip,pareto"
+    )?;
+    for r in results.iter() {
+        let p = &r.point;
+        writeln!(
+            writer,
+            "{},{},{},{},{},{},{},{},{},{},{},{},{},{},{}",
+            p.mapper_num,
+            p.reducer_num,
+            p.tile_sram_size,
+            p.amorph_sram,
+            p.mult_array[0],
+            p.mult_array[1],
+            p.add_array[0],
+            p.add_array[1],
+            p.gemm[0],
+            p.gemm[1],
+            p.gemm[2],
+            r.latency,
+            r.sram,
+            r.offchip,
+            r.pareto
+        )?;
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+pub fn write_json(path: &str, results: &[DseResult]) -> Result<(), Box<dyn Error>> {
+    let mut writer = BufWriter::new(File::create(path)?);
+    let json_list = results.iter().map(|r| r.dump2json()).collect::<Vec<_>>();
+    let pareto = results
+        .iter()
+        .enumerate()
+        .filter(|(_, r)| r.pareto)
+        .map(|(i, _)| i)
+        .collect::<Vec<_>>();
+    serde_json::to_writer_pretty(
+        &mut writer,
+        &json!({ "results": json_list, "pareto": pareto }),
+    )?;
+    writer.flush()?;
+    Ok(())
+}
@@ -0,0 +1,309 @@
+use serde_json::{json, Value};
+use std::collections::BTreeMap;
+use std::error::Error;
+
+use crate::op::{OpTrait, OpType};
+use crate::simulator::MEMORY_ID;
+use crate::util::f64_field;
+
+// Energy in pJ per event and area in um^2 per unit.
+#[derive(Debug, Clone)]
+pub struct EnergyModel {
+    pub mac: f64,
+    pub add: f64,
+    // Per element read or written in the tile's own sram.
+    pub sram_local: f64,
+    // Per element read or written in sram another tile lends, through its network interface.
+    pub sram_remote: f64,
+    // Per element per router traversed.
+    pub hop: f64,
+    pub dram_byte: f64,
+    pub reconfig: f64,
+    pub element_bytes: f64,
+    pub mac_area: f64,
+    pub add_area: f64,
+    // Per sram element.
+    pub sram_area: f64,
+    // Router and network interface of a tile.
+    pub router_area: f64,
+}
+
+impl Default for EnergyModel {
+    fn default() -> EnergyModel {
+        EnergyModel {
+            mac: 1.0,
+            add: 0.1,
+            sram_local: 2.5,
+            sram_remote: 5.0,
+            hop: 1.0,
+            dram_byte: 20.0,
+            reconfig: 50.0,
+            element_bytes: 2.0,
+            mac_area: 400.0,
+            add_area: 60.0,
+            sram_area: 2.0,
+            router_area: 20000.0,
+        }
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy)]
+pub struct OpEnergy {
+    pub compute: f64,
+    pub sram: f64,
+    pub noc: f64,
+    pub dram: f64,
+}
+
+impl OpEnergy {
+    pub fn total(&self) -> f64 {
+        self.compute + self.sram + self.noc + self.dram
+    }
+
+    fn add(&mut self, other: &OpEnergy) {
+        self.compute += other.compute;
+        self.sram += other.sram;
+        self.noc += other.noc;
+        self.dram += other.dram;
+    }
+
+    fn dump2json(&self) -> Value {
+        json!({
+            "compute": self.compute,
+            "sram": self.sram,
+            "noc": self.noc,
+            "dram": self.dram,
+            "total": self.total(),
+        })
+    }
+}
+
+impl EnergyModel {
+    // Any subset of the fields, e.g. {"mac": 0.8, "dram_byte": 15}.
+    pub fn from_json(value: &Value) -> Result<EnergyModel, Box<dyn Error>> {
+        EnergyModel::default().update(value)
+    }
+
+    // This model with the fields value gives replaced.
+    pub fn update(&self, value: &Value) -> Result<EnergyModel, Box<dyn Error>> {
+        let known = self.dump2json();
+        let object = value
+            .as_object()
+            .ok_or(format!("Energy costs should be an object, got {}", value))?;
+        if let Some(key) = object.keys().find(|k| known.get(k.as_str()).is_none()) {
+            return Err(format!("Unknown energy cost `{}`", key).into());
+        }
+        let default = self;
+        let optional = |key: &str, default: f64| match value.get(key) {
+            Some(_) => f64_field(value, key),
+            None => Ok(default),
+        };
+        Ok(EnergyModel {
+            mac: optional("mac", default.mac)?,
+            add: optional("add", default.add)?,
+            sram_local: optional("sram_local", default.sram_local)?,
+            sram_remote: optional("sram_remote", default.sram_remote)?,
+            hop: optional("hop", default.hop)?,
+            dram_byte: optional("dram_byte", default.dram_byte)?,
+            reconfig: optional("reconfig", default.reconfig)?,
+            element_bytes: optional("element_bytes", default.element_bytes)?,
+            mac_area: optional("mac_area", default.mac_area)?,
+            add_area: optional("add_area", default.add_area)?,
+            sram_area: optional("sram_area", default.sram_area)?,
+            router_area: optional("router_area", default.router_area)?,
+        })
+    }
+
+    pub fn dump2json(&self) -> Value {
+        json!({
+            "mac": self.mac,
+            "add": self.add,
+            "sram_local": self.sram_local,
+            "sram_remote": self.sram_remote,
+            "hop": self.hop,
+            "dram_byte": self.dram_byte,
+            "reconfig": self.reconfig,
+            "element_bytes": self.element_bytes,
+            "mac_area": self.mac_area,
+            "add_area": self.add_area,
+            "sram_area": self.sram_area,
+            "router_area": self.router_area,
+        })
+    }
+
+    // A tile to tile transfer reads the source sram and writes the destination
+    // sram, the far one remotely when it is lent sram. A memory transfer touches only
+    // the tile's own sram.
+    pub fn op_energy(&self, op: &dyn OpTrait) -> OpEnergy {
+        let len = op.complexity() as f64;
+        let local = op.sram_accesses() as f64 * self.sram_local;
+        match op.op_type() {
+            OpType::CrossPOp => OpEnergy {
+                compute: len * self.mac,
+                sram: local,
+                ..Default::default()
+            },
+            OpType::VecOp => OpEnergy {
+                compute: len * self.add,
+                sram: local,
+                ..Default::default()
+            },
+            OpType::TransOp if op.modules().contains(&MEMORY_ID) => OpEnergy {
+                sram: len * self.sram_local,
+                dram: len * self.element_bytes * self.dram_byte,
+                ..Default::default()
+            },
+            OpType::TransOp => {
+                let far = if op.lent_sram() {
+                    self.sram_remote
+                } else {
+                    self.sram_local
+                };
+                OpEnergy {
+                    sram: len * (self.sram_local + far),
+                    noc: len * op.hops() as f64 * self.hop,
+                    ..Default::default()
+                }
+            }
+            OpType::ReconfOp => OpEnergy {
+                compute: self.reconfig,
+                ..Default::default()
+            },
+        }
+    }
+
+    // Every tile carries both arrays, its sram and a router.
+    pub fn area(
+        &self,
+        tile_num: usize,
+        mult_array: [usize; 2],
+        add_array: [usize; 2],
+        tile_sram_size: usize,
+    ) -> f64 {
+        let tile = (mult_array[0] * mult_array[1]) as f64 * self.mac_area
+            + (add_array[0] * add_array[1]) as f64 * self.add_area
+            + tile_sram_size as f64 * self.sram_area
+            + self.router_area;
+        tile_num as f64 * tile
+    }
+
+    // Add every op's pJ as "energy" to a dumped trace, a bare list or one with a header.
+    pub fn annotate(&self, trace: &mut Value, op_list: &[Box<dyn OpTrait>]) {
+        let ops = match trace.get_mut("ops") {
+            Some(ops) => ops,
+            None => trace,
+        };
+        if let Some(ops) = ops.as_array_mut() {
+            for (op, value) in op_list.iter().zip(ops.iter_mut()) {
+                value["energy"] = json!(self.op_energy(op.as_ref()).total());
+            }
+        }
+    }
+
+    pub fn report(&self, op_list: &[Box<dyn OpTrait>]) -> EnergyReport {
+        let mut total = OpEnergy::default();
+        let mut by_type: BTreeMap<&'static str, OpEnergy> = BTreeMap::new();
+        for op in op_list.iter() {
+            let energy = self.op_energy(op.as_ref());
+            total.add(&energy);
+            let kind = match op.op_type() {
+                OpType::CrossPOp => "crossproduct",
+                OpType::VecOp => "vector",
+                OpType::TransOp => "transfer",
+                OpType::ReconfOp => "reconfig",
+            };
+            by_type.entry(kind).or_default().add(&energy);
+        }
+        EnergyReport { total, by_type }
+    }
+}
+
+pub struct EnergyReport {
+    pub total: OpEnergy,
+    pub by_type: BTreeMap<&'static str, OpEnergy>,
+}
+
+impl EnergyReport {
+    // pJ, EDP in pJ * cycles and area in um^2.
+    pub fn dump2json(&self, model: &EnergyModel, cycles: usize, area: f64) -> Value {
+        json!({
+            "costs": model.dump2json(),
+            "total": self.total.dump2json(),
+            "by_type": self
+                .by_type
+                .iter()
+                .map(|(kind, e)| (kind.to_string(), e.dump2json()))
+                .collect::<serde_json::Map<_, _>>(),
+            "cycles": cycles,
+            "edp": self.total.total() * cycles as f64,
+            "area": area,
+        })
+    }
+
+    // EDP in pJ * cycles.
+    pub fn print_summary(&self, cycles: usize, area: f64) {
+        let total = self.total.total();
+        let share = |e: f64| 100.0 * e / total.max(f64::MIN_POSITIVE);
+        println!("Energy: {:.3} uJ", total / 1e6);
+        for (name, e) in [
+            ("compute", self.total.compute),
+            ("sram", self.total.sram),
+            ("noc", self.total.noc),
+            ("dram", self.total.dram),
+        ] {
+            println!("  {}: {:.3} uJ ({:.1}%)", name, e / 1e6, share(e));
+        }
+        for (kind, e) in self.by_type.iter() {
+            println!(
+                "  {} ops: {:.3} uJ ({:.1}%)",
+                kind,
+                e.total() / 1e6,
+                share(e.total())
+            );
+        }
+        println!(
+            "EDP: {:.4e} pJ*cycles over {} cycles",
+            total * cycles as f64,
+            cycles
+        );
+        println!("Area: {:.3} mm^2", area / 1e6);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::outer_product::OuterProduct;
+
+    #[test]
+    fn remote_sram_for_lent_sram_only() {
+        let model = EnergyModel::default();
+        let mut dram = vec![];
+        for amorph in [false, true] {
+            let mut outp = OuterProduct::new(4, 4, 256, amorph, [4, 4], [8, 4]);
+            outp.set_gemm(32, 32, 64).unwrap();
+            outp.exec();
+            // Elements moved tile to tile, into or out of lent sram, and to or from memory.
+            let mut moved = [0.0; 3];
+            for op in outp.op_list() {
+                if op.op_type() != OpType::TransOp {
+                    continue;
+                }
+                let kind = match (op.modules().contains(&MEMORY_ID), op.lent_sram()) {
+                    (true, _) => 2,
+                    (false, lent) => lent as usize,
+                };
+                moved[kind] += op.complexity() as f64;
+            }
+            assert_eq!(moved[1] > 0.0, amorph);
+            let report = model.report(outp.op_list());
+            let sram = 2.0 * model.sram_local * moved[0]
+                + (model.sram_local + model.sram_remote) * moved[1]
+                + model.sram_local * moved[2];
+            assert!((report.by_type["transfer"].sram - sram).abs() < 1e-6);
+            dram.push(report.total.dram);
+        }
+        // Lent sram holds partial sums that would otherwise spill to memory.
+        assert!(dram[1] < dram[0]);
+    }
+}
@@ -0,0 +1,36 @@
+use serde_json::{json, Value};
+
+use crate::op::{OpTrait, ReconfOp, TransOp, VecOp};
+use crate::trace::{dump_trace, TraceHeader};
+
+// Op and trace builders shared by the unit tests.
+
+pub fn trans(idx: usize, src: i32, dst: i32, len: usize, deps: &[usize]) -> Box<dyn OpTrait> {
+    Box::new(TransOp::new(
+        idx,
+        src,
+        dst,
+        len,
+        deps.to_vec(),
+        "trans".to_string(),
+    ))
+}
+
+pub fn vec_op(idx: usize, pid: usize, len: usize, deps: &[usize]) -> Box<dyn OpTrait> {
+    Box::new(VecOp::new(idx, pid, len, deps.to_vec(), "vec".to_string()))
+}
+
+pub fn reconf(idx: usize, pid: usize, size: usize, deps: &[usize]) -> Box<dyn OpTrait> {
+    Box::new(ReconfOp::new(
+        idx,
+        pid,
+        size,
+        deps.to_vec(),
+        "reconf".to_string(),
+    ))
+}
+
+// Current-version trace of op_list over module_count modules.
+pub fn trace_json(module_count: usize, op_list: &[Box<dyn OpTrait>]) -> Value {
+    dump_trace(&TraceHeader::new(module_count, json!({})), op_list)
+}
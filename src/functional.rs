@@ -0,0 +1,346 @@
+use std::collections::{BTreeMap, VecDeque};
+use std::error::Error;
+
+use crate::util::{balanced_split, XorShift};
+
+// Where a mapper's partial sums wait for their reducer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Place {
+    Local,
+    // Sram lent by this mapper.
+    Remote(usize),
+    Memory,
+}
+
+// Offset in the reducer's block and the values from there on.
+type Chunk = (usize, Vec<i64>);
+
+// Small values in -8..=8, so chained products stay far from overflow.
+pub fn random_matrix(len: usize, rng: &mut XorShift) -> Vec<i64> {
+    (0..len).map(|_| rng.below(17) as i64 - 8).collect()
+}
+
+// Carries real matrices through the outer-product dataflow. `OuterProduct::exec` reports
+// every cross-product, partial-sum move, reduction and output, blocks are flattened
+// row-major over the reducer's tile.
+pub struct FunctionalGemm {
+    m: usize,
+    n: usize,
+    k: usize,
+    // Reducer grid para_m x para_n, tiles along each dim differ by at most 1.
+    para: (usize, usize),
+    // m x k and k x n, row-major.
+    a: Vec<i64>,
+    b: Vec<i64>,
+    c: Vec<i64>,
+    // Mapper -> m x n partial sums of the current iteration.
+    partial: BTreeMap<usize, Vec<i64>>,
+    // (reducer, place) -> (offset in block, values) not yet reduced.
+    buffers: BTreeMap<(usize, Place), VecDeque<Chunk>>,
+    // Per reducer, running sum of its tile.
+    acc: Vec<Vec<i64>>,
+    // (reducer, mapper) -> elements delivered in the current iteration.
+    delivered: BTreeMap<(usize, usize), usize>,
+    errors: Vec<String>,
+}
+
+impl FunctionalGemm {
+    pub fn new(m: usize, n: usize, k: usize, para: (usize, usize), seed: u64) -> FunctionalGemm {
+        let mut rng = XorShift::new(seed);
+        let a = random_matrix(m * k, &mut rng);
+        let b = random_matrix(k * n, &mut rng);
+        FunctionalGemm::with_operands(m, n, k, para, a, b)
+    }
+
+    // a is m x k and b is k x n, row-major.
+    pub fn with_operands(
+        m: usize,
+        n: usize,
+        k: usize,
+        para: (usize, usize),
+        a: Vec<i64>,
+        b: Vec<i64>,
+    ) -> FunctionalGemm {
+        FunctionalGemm {
+            m,
+            n,
+            k,
+            para,
+            a,
+            b,
+            c: vec![0; m * n],
+            partial: BTreeMap::new(),
+            buffers: BTreeMap::new(),
+            acc: vec![vec![]; para.0 * para.1],
+            delivered: BTreeMap::new(),
+            errors: vec![],
+        }
+    }
+
+    // First row, rows, first col and cols of the output reducer r owns, split like
+    // `OuterProduct` does.
+    fn block(&self, r: usize) -> (usize, usize, usize, usize) {
+        let (row0, row1) = balanced_split(self.m, self.para.0, r / self.para.1);
+        let (col0, col1) = balanced_split(self.n, self.para.1, r % self.para.1);
+        (row0, row1 - row0, col0, col1 - col0)
+    }
+
+    fn block_len(&self, r: usize) -> usize {
+        let (_, rows, _, cols) = self.block(r);
+        rows * cols
+    }
+
+    // Mapper mid multiplies A[:, k_lo..k_hi] by B[k_lo..k_hi, :], k_hi is clipped to k.
+    pub fn map(&mut self, mid: usize, k_lo: usize, k_hi: usize) {
+        let mut partial = vec![0; self.m * self.n];
+        for kk in k_lo..k_hi.min(self.k) {
+            for i in 0..self.m {
+                let a = self.a[i * self.k + kk];
+                for j in 0..self.n {
+                    partial[i * self.n + j] += a * self.b[kk * self.n + j];
+                }
+            }
+        }
+        self.partial.insert(mid, partial);
+    }
+
+    // Mapper mid moves elements offset..offset + len of its block for reducer r to place.
+    pub fn send(&mut self, mid: usize, r: usize, place: Place, offset: usize, len: usize) {
+        if len == 0 {
+            return;
+        }
+        let block_len = self.block_len(r);
+        if offset + len > block_len {
+            self.errors.push(format!(
+                "Mapper {} sends elements {}..{} to reducer {} of a {}-element block",
+                mid,
+                offset,
+                offset + len,
+                r,
+                block_len
+            ));
+        }
+        let Some(partial) = self.partial.get(&mid) else {
+            self.errors.push(format!(
+                "Mapper {} sends to reducer {} before its cross-product",
+                mid, r
+            ));
+            return;
+        };
+        let (row0, _, col0, cols) = self.block(r);
+        let values = (offset..(offset + len).min(block_len))
+            .map(|e| partial[(row0 + e / cols) * self.n + col0 + e % cols])
+            .collect::<Vec<_>>();
+        *self.delivered.entry((r, mid)).or_default() += len;
+        self.buffers
+            .entry((r, place))
+            .or_default()
+            .push_back((offset, values));
+    }
+
+    // Reducer r adds len elements waiting at place into its tile.
+    pub fn reduce(&mut self, r: usize, place: Place, len: usize) {
+        let block_len = self.block_len(r);
+        if self.acc[r].len() != block_len {
+            self.acc[r] = vec![0; block_len];
+        }
+        let mut consumed = 0;
+        while consumed < len {
+            let Some((offset, values)) = self
+                .buffers
+                .get_mut(&(r, place))
+                .and_then(|b| b.pop_front())
+            else {
+                self.errors.push(format!(
+                    "Reducer {} reduces {} elements from {:?} but only {} arrived",
+                    r, len, place, consumed
+                ));
+                return;
+            };
+            consumed += values.len();
+            for (e, v) in values.into_iter().enumerate() {
+                self.acc[r][offset + e] += v;
+            }
+        }
+    }
+
+    // Reducer r writes its running sum back to C.
+    pub fn output(&mut self, r: usize) {
+        let (row0, rows, col0, cols) = self.block(r);
+        if self.acc[r].len() != rows * cols {
+            return;
+        }
+        for e in 0..rows * cols {
+            self.c[(row0 + e / cols) * self.n + col0 + e % cols] = self.acc[r][e];
+        }
+    }
+
+    // Every mapper of the iteration must have delivered each reducer's whole block,
+    // and every delivered element must have been reduced.
+    pub fn end_iteration(&mut self, iter: usize) {
+        let mids = self.partial.keys().copied().collect::<Vec<_>>();
+        for r in 0..self.acc.len() {
+            for mid in mids.iter() {
+                let delivered = self.delivered.get(&(r, *mid)).copied().unwrap_or(0);
+                if delivered != self.block_len(r) {
+                    self.errors.push(format!(
+                        "Iteration {}: mapper {} delivered {} of {} elements to reducer {}",
+                        iter,
+                        mid,
+                        delivered,
+                        self.block_len(r),
+                        r
+                    ));
+                }
+            }
+        }
+        for ((r, place), chunks) in self.buffers.iter() {
+            if !chunks.is_empty() {
+                self.errors.push(format!(
+                    "Iteration {}: {} chunks for reducer {} left unreduced in {:?}",
+                    iter,
+                    chunks.len(),
+                    r,
+                    place
+                ));
+            }
+        }
+        self.partial.clear();
+        self.buffers.clear();
+        self.delivered.clear();
+    }
+
+    // C as written back by the reducers.
+    pub fn result(&self) -> &[i64] {
+        &self.c
+    }
+
+    // Compare C against a direct A x B.
+    pub fn verify(&self) -> Result<(), Box<dyn Error>> {
+        let mut errors = self.errors.clone();
+        let mut mismatched = 0;
+        for i in 0..self.m {
+            for j in 0..self.n {
+                let expected = (0..self.k)
+                    .map(|kk| self.a[i * self.k + kk] * self.b[kk * self.n + j])
+                    .sum::<i64>();
+                let got = self.c[i * self.n + j];
+                if got != expected {
+                    if mismatched < 8 {
+                        errors.push(format!(
+                            "C[{}][{}]: expected {}, got {}",
+                            i, j, expected, got
+                        ));
+                    }
+                    mismatched += 1;
+                }
+            }
+        }
+        if mismatched > 0 {
+            errors.push(format!(
+                "{} of {} elements differ",
+                mismatched,
+                self.m * self.n
+            ));
+        }
+        if errors.is_empty() {
+            return Ok(());
+        }
+        let total = errors.len();
+        errors.truncate(16);
+        if total > errors.len() {
+            errors.push(format!("{} more", total - errors.len()));
+        }
+        Err(format!("GEMM mismatch: {}", errors.join("; ")).into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::outer_product::{Chain, OuterProduct, ReducerGrid, Tiling};
+
+    // One mapper covers all of k and sends every block to its reducer locally.
+    fn single_pass(gemm: &mut FunctionalGemm, reducers: usize) {
+        gemm.map(0, 0, usize::MAX);
+        for r in 0..reducers {
+            let len = gemm.block_len(r);
+            gemm.send(0, r, Place::Local, 0, len);
+            gemm.reduce(r, Place::Local, len);
+            gemm.output(r);
+        }
+        gemm.end_iteration(0);
+    }
+
+    #[test]
+    fn dataflow() {
+        let mut gemm = FunctionalGemm::new(4, 6, 3, (2, 2), 1);
+        single_pass(&mut gemm, 4);
+        gemm.verify().unwrap();
+    }
+
+    #[test]
+    fn missing_reduce_fails() {
+        let mut gemm = FunctionalGemm::new(4, 4, 2, (2, 2), 1);
+        gemm.map(0, 0, 2);
+        for r in 0..3 {
+            gemm.send(0, r, Place::Local, 0, 4);
+            gemm.reduce(r, Place::Local, 4);
+            gemm.output(r);
+        }
+        gemm.end_iteration(0);
+        assert!(gemm.verify().is_err());
+    }
+
+    #[test]
+    fn outer_product() {
+        for amorph in [false, true] {
+            let mut outp = OuterProduct::new(4, 4, 256, amorph, [4, 4], [8, 4]);
+            outp.set_gemm(32, 32, 64).unwrap();
+            outp.set_functional(7);
+            outp.exec();
+            outp.verify().unwrap();
+        }
+    }
+
+    #[test]
+    fn edge_blocks() {
+        let gemm = FunctionalGemm::new(5, 7, 3, (2, 3), 1);
+        // Rows split 3 + 2, cols 3 + 2 + 2.
+        assert_eq!(gemm.block(0), (0, 3, 0, 3));
+        assert_eq!(gemm.block(2), (0, 3, 5, 2));
+        assert_eq!(gemm.block(4), (3, 2, 3, 2));
+        assert_eq!((0..6).map(|r| gemm.block_len(r)).sum::<usize>(), 5 * 7);
+        let mut gemm = gemm;
+        single_pass(&mut gemm, 6);
+        gemm.verify().unwrap();
+    }
+
+    #[test]
+    fn outer_product_edge_tiles() {
+        for (grid, amorph) in [
+            (ReducerGrid::Square, false),
+            (ReducerGrid::Grid(2, 3), true),
+            (ReducerGrid::Rows, false),
+            (ReducerGrid::Cols, true),
+        ] {
+            let mut outp = OuterProduct::new(4, 6, 256, amorph, [4, 4], [8, 4]);
+            outp.set_tiling(Tiling::Fixed { mapper_k: 3, grid });
+            outp.set_gemm(37, 29, 19).unwrap();
+            outp.set_functional(7);
+            outp.exec();
+            outp.verify().unwrap();
+        }
+    }
+
+    #[test]
+    fn outer_product_chain() {
+        for chain in [Chain::Batch, Chain::Memory, Chain::OnChip] {
+            let mut outp = OuterProduct::new(4, 4, 1024, false, [4, 4], [8, 4]);
+            outp.set_gemm(12, 10, 9).unwrap();
+            outp.set_functional(3);
+            outp.exec_chain(&[[12, 6, 10]], chain).unwrap();
+            outp.verify().unwrap();
+        }
+    }
+}
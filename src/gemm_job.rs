@@ -0,0 +1,252 @@
+use serde_json::json;
+use std::collections::BTreeMap;
+use std::error::Error;
+
+use crate::config::{GemmConfig, HardwareConfig};
+use crate::functional::random_matrix;
+use crate::mapreduce::{Engine, MapReduce, MapReduceJob};
+use crate::outer_product::OuterProduct;
+use crate::trace::TraceHeader;
+use crate::util::{balanced_split, XorShift};
+
+// C = A x B as records. A split is a k slice of A's columns and B's rows, mapping it
+// yields one partial-sum tile per reducer of the para_m x para_n grid, keyed by the
+// reducer, and reducers add the tiles into C. Sizes are elements, as in `OuterProduct`.
+pub struct GemmJob {
+    m: usize,
+    n: usize,
+    k: usize,
+    para: (usize, usize),
+    // m x k and k x n, row-major.
+    a: Vec<i64>,
+    b: Vec<i64>,
+    c: Vec<i64>,
+    // First k of the next split.
+    next_k: usize,
+}
+
+impl GemmJob {
+    // Rows and cols of C reducer r owns, split like `OuterProduct` does.
+    fn tile(&self, r: usize) -> [(usize, usize); 2] {
+        [
+            balanced_split(self.m, self.para.0, r / self.para.1),
+            balanced_split(self.n, self.para.1, r % self.para.1),
+        ]
+    }
+
+    // Elements of the largest tile.
+    fn max_tile(&self) -> usize {
+        self.m.div_ceil(self.para.0) * self.n.div_ceil(self.para.1)
+    }
+}
+
+impl MapReduceJob for GemmJob {
+    // k_lo..k_hi.
+    type Split = (usize, usize);
+    // Reducer index.
+    type Key = usize;
+    // Partial sums of the reducer's tile, row-major.
+    type Value = Vec<i64>;
+
+    // A split of max_size k carries max_size columns of A and rows of B.
+    fn next_split(&mut self, max_size: usize) -> Option<((usize, usize), usize)> {
+        if self.next_k >= self.k {
+            return None;
+        }
+        let split = (self.next_k, (self.next_k + max_size.max(1)).min(self.k));
+        self.next_k = split.1;
+        Some((split, (split.1 - split.0) * (self.m + self.n)))
+    }
+
+    fn map(&self, (k_lo, k_hi): (usize, usize)) -> Vec<(usize, Vec<i64>)> {
+        (0..self.para.0 * self.para.1)
+            .map(|r| {
+                let [rows, cols] = self.tile(r);
+                let mut tile = Vec::with_capacity((rows.1 - rows.0) * (cols.1 - cols.0));
+                for i in rows.0..rows.1 {
+                    for j in cols.0..cols.1 {
+                        tile.push(
+                            (k_lo..k_hi)
+                                .map(|kk| self.a[i * self.k + kk] * self.b[kk * self.n + j])
+                                .sum(),
+                        );
+                    }
+                }
+                (r, tile)
+            })
+            .collect()
+    }
+
+    fn partition(&self, key: &usize, reducer_num: usize) -> usize {
+        key % reducer_num
+    }
+
+    fn combine(&self, acc: &mut Vec<i64>, value: Vec<i64>) {
+        for (a, v) in acc.iter_mut().zip(value) {
+            *a += v;
+        }
+    }
+
+    // A reducer may output its tile over several rounds, each adds to C.
+    fn reduce(&mut self, _reducer: usize, records: BTreeMap<usize, Vec<i64>>) {
+        for (r, tile) in records {
+            let [rows, cols] = self.tile(r);
+            let width = cols.1 - cols.0;
+            for (idx, v) in tile.into_iter().enumerate() {
+                self.c[(rows.0 + idx / width) * self.n + cols.0 + idx % width] += v;
+            }
+        }
+    }
+
+    fn record_bytes(&self, _key: &usize, value: &Vec<i64>) -> usize {
+        value.len()
+    }
+
+    // MACs of the split's cross-product.
+    fn map_cost(&self, split_bytes: usize) -> usize {
+        split_bytes / (self.m + self.n) * self.m * self.n
+    }
+
+    fn combine_cost(&self, records: usize) -> usize {
+        records * self.max_tile()
+    }
+
+    fn reduce_cost(&self, records: usize) -> usize {
+        records * self.max_tile()
+    }
+
+    fn name(&self) -> String {
+        "tile".to_string()
+    }
+}
+
+pub type GemmMapReduce = MapReduce<GemmJob>;
+
+impl MapReduce<GemmJob> {
+    // Random A and B, tiled as `OuterProduct` tiles the same GEMM. Mappers buffer one
+    // mapper_k split, reducers as many tiles as their sram holds. Under amorph-sram
+    // mappers keep one unit cross-product and lend the rest.
+    pub fn from_config(
+        hw: &HardwareConfig,
+        gemm: &GemmConfig,
+        seed: u64,
+    ) -> Result<GemmMapReduce, Box<dyn Error>> {
+        let (mapper_k, para) = OuterProduct::from_config(hw, gemm)?.tiling();
+        let mut rng = XorShift::new(seed);
+        let job = GemmJob {
+            m: gemm.m,
+            n: gemm.n,
+            k: gemm.k,
+            para,
+            a: random_matrix(gemm.m * gemm.k, &mut rng),
+            b: random_matrix(gemm.k * gemm.n, &mut rng),
+            c: vec![0; gemm.m * gemm.n],
+            next_k: 0,
+        };
+        let max_tile = job.max_tile();
+        let tiles = |elements: usize| elements / max_tile;
+        let engine = Engine::new(
+            hw.mapper_num,
+            hw.reducer_num,
+            mapper_k,
+            tiles(hw.tile_sram_size),
+        );
+        let mut gemm = MapReduce::new(engine, job);
+        gemm.engine.placement = hw.placement;
+        if hw.amorph_sram {
+            for mid in gemm.engine.mids.clone() {
+                gemm.engine.set_sram_size(mid, tiles(hw.tile_sram_size));
+            }
+            let unit = hw.mult_array.iter().sum::<usize>();
+            gemm.set_amorph_sram(tiles(hw.tile_sram_size.saturating_sub(unit)));
+        }
+        Ok(gemm)
+    }
+
+    pub fn trace_header(&self) -> TraceHeader {
+        self.engine.trace_header(json!({
+            "workload": "outer-product-records",
+            "mapper_num": self.engine.mapper_num,
+            "reducer_num": self.engine.reducer_num,
+            "mapper_k": self.engine.mapper_buf_size,
+            "reducer_tiles": self.engine.reducer_buf_size,
+            "reducer_grid": [self.job.para.0, self.job.para.1],
+            "overflow": format!("{:?}", self.overflow()).to_lowercase(),
+            "amorph_sram": self.amorph_sram(),
+            "gemm": [self.job.m, self.job.n, self.job.k],
+            "load": self.load_report().dump2json(),
+        }))
+    }
+
+    // Compare the reduced C against a direct A x B.
+    pub fn verify(&self) -> Result<(), Box<dyn Error>> {
+        let job = &self.job;
+        let mut mismatched = vec![];
+        for i in 0..job.m {
+            for j in 0..job.n {
+                let expected = (0..job.k)
+                    .map(|kk| job.a[i * job.k + kk] * job.b[kk * job.n + j])
+                    .sum::<i64>();
+                let got = job.c[i * job.n + j];
+                if got != expected {
+                    mismatched.push(format!(
+                        "C[{}][{}]: expected {}, got {}",
+                        i, j, expected, got
+                    ));
+                }
+            }
+        }
+        if mismatched.is_empty() {
+            return Ok(());
+        }
+        let total = mismatched.len();
+        mismatched.truncate(8);
+        Err(format!(
+            "GEMM mismatch, {} of {} elements differ: {}",
+            total,
+            job.m * job.n,
+            mismatched.join("; ")
+        )
+        .into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{Experiment, WorkloadConfig};
+
+    #[test]
+    fn reduces_to_reference() {
+        for amorph_sram in [false, true] {
+            let exp = Experiment::from_json(&json!({
+                "hardware": {
+                    "mapper_num": 4, "reducer_num": 6, "tile_sram_size": 512,
+                    "mult_array": [4, 4], "add_array": [8, 4], "amorph_sram": amorph_sram,
+                },
+                "workload": {
+                    "type": "outer-product", "gemm": [37, 29, 19],
+                    "tiling": { "mapper_k": 3, "reducer_grid": [2, 3] },
+                },
+            }))
+            .unwrap();
+            let WorkloadConfig::OuterProduct(gemm) = &exp.workload else {
+                unreachable!()
+            };
+            let mut job = GemmMapReduce::from_config(&exp.hardware, gemm, 5).unwrap();
+            job.run();
+            job.verify().unwrap();
+            // Tiles outnumber the reducer buffers, so some wait remote or in memory.
+            let report = job.load_report();
+            assert!(
+                report
+                    .spilled
+                    .iter()
+                    .chain(report.remote.iter())
+                    .sum::<usize>()
+                    > 0
+            );
+            assert_eq!(report.remote.iter().sum::<usize>() > 0, amorph_sram);
+        }
+    }
+}
@@ -1,27 +1,36 @@
+mod analysis;
+mod cli;
+mod config;
+mod conv;
+mod dram;
+mod dse;
+mod energy;
+#[cfg(test)]
+mod fixtures;
+mod functional;
+mod gemm_job;
+mod mapreduce;
+mod noc;
 mod op;
 mod outer_product;
+mod partition;
+mod simulator;
+mod topology;
+mod trace;
 mod util;
 mod word_count;
 
-use outer_product::OuterProduct;
-use word_count::WordCount;
-use serde_json::{Value, Map};
-use std::fs::File;
-use std::io::{BufWriter, Write};
+use std::env;
+use std::process;
 
 fn main() {
-    let mut outp = OuterProduct::new(8, 8, 4096, false, [32, 32], [64, 32]);
-    outp.set_gemm(128, 128, 512);
-    outp.exec();
-
-    let mut json_list = vec![];
-    for op in outp.op_list.iter() {
-        println!("{}", op.format_op());
-        json_list.push(op.dump2json());
+    let args = env::args().skip(1).collect::<Vec<_>>();
+    if args.is_empty() {
+        eprintln!("{}", cli::USAGE);
+        process::exit(1);
+    }
+    if let Err(e) = cli::run(&args) {
+        eprintln!("Error: {}", e);
+        process::exit(1);
     }
-    let file = File::create("result/outer-product-no-amorsram.json").unwrap();
-    let mut writer = BufWriter::new(file);
-    serde_json::to_writer_pretty(&mut writer, &json_list).unwrap();
-    writer.flush().unwrap();
-    // println!("{:?}", json_list);
-}
\ No newline at end of file
+}
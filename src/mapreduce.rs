@@ -0,0 +1,889 @@
+use serde_json::{json, Value};
+use std::collections::{BTreeMap, VecDeque};
+use std::error::Error;
+use std::fmt::Debug;
+
+use crate::op::{CrossPOp, OpTrait, ReconfOp, TransOp, VecOp};
+use crate::partition::LoadReport;
+use crate::simulator::MEMORY_ID;
+use crate::topology::{Mesh, Placement};
+use crate::trace::TraceHeader;
+use crate::util::Tik;
+
+// Module ids, sram accounting and op emission shared by every workload.
+// Mappers take pids 0..mapper_num, reducers follow.
+pub struct Engine {
+    pub mapper_num: usize,
+    pub reducer_num: usize,
+    pub mapper_buf_size: usize,
+    pub reducer_buf_size: usize,
+    pub op_list: Vec<Box<dyn OpTrait>>,
+    tik: Tik,
+    // Mapper / reducer index -> pid.
+    pub mids: Vec<usize>,
+    pub rids: Vec<usize>,
+    // Pid -> sram size.
+    pub sram_sizes: Vec<usize>,
+    // Pid -> sram left after lending.
+    pub local_srams: Vec<usize>,
+    // Pid -> sram it never lends.
+    pub reserved: Vec<usize>,
+    // Lender pid -> [borrower pid, size].
+    pub remote_alloc: BTreeMap<usize, Vec<[usize; 2]>>,
+    // Borrower pid -> [lender pid, size].
+    pub remote_hold: BTreeMap<usize, Vec<[usize; 2]>>,
+    pub mesh: Mesh,
+    pub placement: Placement,
+    // Print per-split, per-record and per-phase progress.
+    pub verbose: bool,
+}
+
+impl Engine {
+    pub fn new(
+        mapper_num: usize,
+        reducer_num: usize,
+        mapper_buf_size: usize,
+        reducer_buf_size: usize,
+    ) -> Engine {
+        let mids = (0..mapper_num).collect::<Vec<usize>>();
+        let rids = (mapper_num..mapper_num + reducer_num).collect::<Vec<usize>>();
+        let mut local_srams = vec![mapper_buf_size; mapper_num];
+        local_srams.extend(vec![reducer_buf_size; reducer_num]);
+        let mut remote_alloc: BTreeMap<usize, Vec<[usize; 2]>> = BTreeMap::new();
+        let mut remote_hold: BTreeMap<usize, Vec<[usize; 2]>> = BTreeMap::new();
+        for mid in mids.iter() {
+            remote_alloc.entry(*mid).or_default();
+        }
+        for rid in rids.iter() {
+            remote_hold.entry(*rid).or_default();
+        }
+        Engine {
+            mapper_num,
+            reducer_num,
+            mapper_buf_size,
+            reducer_buf_size,
+            op_list: vec![],
+            tik: Tik::new(),
+            mids,
+            rids,
+            sram_sizes: local_srams.clone(),
+            reserved: vec![0; local_srams.len()],
+            local_srams,
+            remote_alloc,
+            remote_hold,
+            mesh: Mesh::fit(mapper_num + reducer_num),
+            placement: Placement::Sequential,
+            verbose: false,
+        }
+    }
+
+    pub fn module_num(&self) -> usize {
+        self.mapper_num + self.reducer_num
+    }
+
+    // Resize the sram of pid before anything is lent.
+    pub fn set_sram_size(&mut self, pid: usize, size: usize) {
+        self.sram_sizes[pid] = size;
+        self.local_srams[pid] = size;
+    }
+
+    // Lend `sizes[r]` of mapper sram to reducer r, walking mappers in order.
+    pub fn lend_sram(&mut self, sizes: &[usize]) {
+        for (rid, size) in self.rids.clone().into_iter().zip(sizes.iter()) {
+            self.borrow_sram(rid, *size);
+        }
+    }
+
+    // Return all lent sram to the mappers.
+    pub fn release_sram(&mut self) {
+        for mid in self.mids.iter() {
+            self.local_srams[*mid] = self.sram_sizes[*mid];
+            self.remote_alloc.insert(*mid, vec![]);
+        }
+        for rid in self.rids.iter() {
+            self.remote_hold.insert(*rid, vec![]);
+        }
+    }
+
+    pub fn print_remote_alloc(&self) {
+        for (mid, lent) in self.remote_alloc.iter() {
+            if !lent.is_empty() {
+                println!("  mapper {} lends {:?}", mid, lent);
+            }
+        }
+        for rid in self.rids.iter() {
+            println!("  reducer {} holds {}", rid, self.remote_size(*rid));
+        }
+    }
+
+    // Mappers in the order `rid` borrows from them.
+    fn lenders(&self, rid: usize) -> Vec<usize> {
+        let mut mids = self.mids.clone();
+        if self.placement == Placement::Nearest {
+            mids.sort_by_key(|mid| self.mesh.hops(*mid as i32, rid as i32));
+        }
+        mids
+    }
+
+    // Borrow up to `size` of mapper sram for `rid`, returns the size borrowed.
+    pub fn borrow_sram(&mut self, rid: usize, size: usize) -> usize {
+        let mut remain_unalloc = size;
+        for mid in self.lenders(rid) {
+            if remain_unalloc == 0 {
+                break;
+            }
+            let lendable = self.local_srams[mid].saturating_sub(self.reserved[mid]);
+            if lendable == 0 {
+                continue;
+            }
+            let alloc_size = lendable.min(remain_unalloc);
+            self.local_srams[mid] -= alloc_size;
+            remain_unalloc -= alloc_size;
+            self.remote_alloc
+                .entry(mid)
+                .or_default()
+                .push([rid, alloc_size]);
+            self.remote_hold
+                .entry(rid)
+                .or_default()
+                .push([mid, alloc_size]);
+        }
+        size - remain_unalloc
+    }
+
+    // Sram a module holds on other modules.
+    pub fn remote_size(&self, pid: usize) -> usize {
+        self.remote_hold
+            .get(&pid)
+            .map(|h| h.iter().map(|r| r[1]).sum())
+            .unwrap_or(0)
+    }
+
+    // Push an op and return its index.
+    pub fn trans(
+        &mut self,
+        src: i32,
+        dst: i32,
+        length: usize,
+        deps: Vec<usize>,
+        content: String,
+    ) -> usize {
+        let op = TransOp::new(self.tik.tik(), src, dst, length, deps, content)
+            .with_hops(self.mesh.hops(src, dst));
+        let idx = op.idx;
+        self.op_list.push(Box::new(op));
+        idx
+    }
+
+    // A store into or fetch from the sram a mapper lends.
+    pub fn trans_lent(
+        &mut self,
+        src: i32,
+        dst: i32,
+        length: usize,
+        deps: Vec<usize>,
+        content: String,
+    ) -> usize {
+        let op = TransOp::new(self.tik.tik(), src, dst, length, deps, content)
+            .with_hops(self.mesh.hops(src, dst))
+            .with_lent(true);
+        let idx = op.idx;
+        self.op_list.push(Box::new(op));
+        idx
+    }
+
+    pub fn load(&mut self, pid: usize, length: usize, deps: Vec<usize>, content: String) -> usize {
+        self.trans(MEMORY_ID, pid as i32, length, deps, content)
+    }
+
+    pub fn store(&mut self, pid: usize, length: usize, deps: Vec<usize>, content: String) -> usize {
+        self.trans(pid as i32, MEMORY_ID, length, deps, content)
+    }
+
+    pub fn vec(&mut self, pid: usize, length: usize, deps: Vec<usize>, content: String) -> usize {
+        let op = VecOp::new(self.tik.tik(), pid, length, deps, content);
+        let idx = op.idx;
+        self.op_list.push(Box::new(op));
+        idx
+    }
+
+    pub fn crossp(
+        &mut self,
+        pid: usize,
+        shape: [usize; 3],
+        deps: Vec<usize>,
+        content: String,
+    ) -> usize {
+        let [k, m, n] = shape;
+        let op = CrossPOp::new(self.tik.tik(), pid, k, m, n, deps, content);
+        let idx = op.idx;
+        self.op_list.push(Box::new(op));
+        idx
+    }
+
+    pub fn reconf(&mut self, pid: usize, size: usize, deps: Vec<usize>, content: String) -> usize {
+        let op = ReconfOp::new(self.tik.tik(), pid, size, deps, content);
+        let idx = op.idx;
+        self.op_list.push(Box::new(op));
+        idx
+    }
+
+    pub fn trace_header(&self, mut generator: Value) -> TraceHeader {
+        generator["mesh"] = json!([self.mesh.cols, self.mesh.rows]);
+        generator["placement"] = json!(self.placement.name());
+        TraceHeader::new(self.module_num(), generator)
+    }
+}
+
+// How rentable mapper sram is split among reducers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AllocPolicy {
+    // rentable / reducer_num each, regardless of demand.
+    Equal,
+    // Equal share, capped at each reducer's demand.
+    DemandCapped,
+    // Shares proportional to demand, capped at demand.
+    Proportional,
+    // Water-filling over demand, minimises the total spill and then the
+    // largest spill of any reducer.
+    MinSpill,
+}
+
+impl AllocPolicy {
+    pub fn from_name(name: &str) -> Result<AllocPolicy, Box<dyn Error>> {
+        match name {
+            "equal" => Ok(AllocPolicy::Equal),
+            "demand-capped" => Ok(AllocPolicy::DemandCapped),
+            "proportional" => Ok(AllocPolicy::Proportional),
+            "min-spill" => Ok(AllocPolicy::MinSpill),
+            p => Err(format!("Unknown allocation policy `{}`", p).into()),
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            AllocPolicy::Equal => "equal",
+            AllocPolicy::DemandCapped => "demand-capped",
+            AllocPolicy::Proportional => "proportional",
+            AllocPolicy::MinSpill => "min-spill",
+        }
+    }
+
+    // Remote sram of each reducer given the rentable total and the sram
+    // each reducer lacks.
+    pub fn allocate(&self, rentable: usize, demands: &[usize]) -> Vec<usize> {
+        let reducer_num = demands.len().max(1);
+        let total_demand = demands.iter().sum::<usize>();
+        match self {
+            AllocPolicy::Equal => vec![rentable / reducer_num; demands.len()],
+            AllocPolicy::DemandCapped => demands
+                .iter()
+                .map(|d| (rentable / reducer_num).min(*d))
+                .collect(),
+            _ if total_demand <= rentable => demands.to_vec(),
+            AllocPolicy::Proportional => demands
+                .iter()
+                .map(|d| (rentable as u128 * *d as u128 / total_demand as u128) as usize)
+                .collect(),
+            AllocPolicy::MinSpill => {
+                // Smallest spill level whose residual demand fits.
+                let residual = |level: usize| {
+                    demands
+                        .iter()
+                        .map(|d| d.saturating_sub(level))
+                        .sum::<usize>()
+                };
+                let (mut lo, mut hi) = (0, *demands.iter().max().unwrap());
+                while lo < hi {
+                    let mid = (lo + hi) / 2;
+                    if residual(mid) <= rentable {
+                        hi = mid;
+                    } else {
+                        lo = mid + 1;
+                    }
+                }
+                let mut alloc = demands
+                    .iter()
+                    .map(|d| d.saturating_sub(lo))
+                    .collect::<Vec<_>>();
+                // Hand out what is left one unit at a time.
+                let mut left = rentable - residual(lo);
+                for (a, d) in alloc.iter_mut().zip(demands.iter()) {
+                    if left == 0 {
+                        break;
+                    }
+                    if *a < *d {
+                        *a += 1;
+                        left -= 1;
+                    }
+                }
+                alloc
+            }
+        }
+    }
+}
+
+// A workload expressed as map / partition / combine / reduce. `MapReduce`
+// drives it over an `Engine` and emits the load, map, combine, shuffle,
+// reduce and output ops. `WordCountJob` and `GemmJob` implement it, the latter
+// shuffles GEMM partial-sum tiles as records. `OuterProduct` keeps its own
+// dataflow on the `Engine` for what records cannot express: per-iteration
+// remote allocation, reconfiguration and chaining GEMMs on chip.
+pub trait MapReduceJob {
+    type Split;
+    type Key: Ord + Clone + Debug;
+    type Value: Clone + Debug;
+
+    // Next input split of at most `max_size` units and its size in bytes,
+    // None once the input is exhausted.
+    fn next_split(&mut self, max_size: usize) -> Option<(Self::Split, usize)>;
+    fn map(&self, split: Self::Split) -> Vec<(Self::Key, Self::Value)>;
+    // Reducer index in 0..reducer_num.
+    fn partition(&self, key: &Self::Key, reducer_num: usize) -> usize;
+    // Fold a value into the accumulated value of the same key.
+    fn combine(&self, acc: &mut Self::Value, value: Self::Value);
+    // Consume the merged records of one reduce round.
+    fn reduce(&mut self, reducer: usize, records: BTreeMap<Self::Key, Self::Value>);
+
+    // Whether mappers combine their records before the shuffle.
+    fn use_combiner(&self) -> bool {
+        false
+    }
+    // Bytes of a shuffled record.
+    fn record_bytes(&self, key: &Self::Key, value: &Self::Value) -> usize;
+    // Bytes of a reduced record written back to memory.
+    fn output_bytes(&self, key: &Self::Key, value: &Self::Value) -> usize {
+        self.record_bytes(key, value)
+    }
+    // Vector lengths of the map, combine and reduce ops.
+    fn map_cost(&self, split_bytes: usize) -> usize {
+        split_bytes
+    }
+    fn combine_cost(&self, records: usize) -> usize {
+        records
+    }
+    fn reduce_cost(&self, records: usize) -> usize {
+        records
+    }
+    fn name(&self) -> String;
+}
+
+type Records<J> = Vec<(<J as MapReduceJob>::Key, <J as MapReduceJob>::Value)>;
+// Records parked outside the reducer and the ops that put them there.
+type Stored<J> = (Records<J>, Vec<usize>);
+
+// What a mapper does when the target reducer buffer is full.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Overflow {
+    // Wait for the reducer to reduce and drain its buffer.
+    Stall,
+    // Spill the excess to memory, the reducer fetches it back when reducing.
+    Spill,
+}
+
+impl Overflow {
+    pub fn from_name(name: &str) -> Result<Overflow, Box<dyn Error>> {
+        match name {
+            "stall" => Ok(Overflow::Stall),
+            "spill" => Ok(Overflow::Spill),
+            o => Err(format!("Unknown overflow policy `{}`", o).into()),
+        }
+    }
+}
+
+fn merge<J: MapReduceJob>(
+    job: &J,
+    board: &mut BTreeMap<J::Key, J::Value>,
+    records: impl IntoIterator<Item = (J::Key, J::Value)>,
+) {
+    for (key, value) in records {
+        match board.get_mut(&key) {
+            Some(acc) => job.combine(acc, value),
+            None => {
+                board.insert(key, value);
+            }
+        }
+    }
+}
+
+pub struct MapReduce<J: MapReduceJob> {
+    pub engine: Engine,
+    pub job: J,
+    overflow: Overflow,
+    // Reducers borrow idle mapper sram when their buffer is full.
+    amorph_sram: bool,
+    // Loaded splits with the op that loaded them.
+    mapper_buffer: Vec<VecDeque<(J::Split, usize, usize)>>,
+    // Holds at most `engine.local_srams[rid]` records.
+    reducer_buffer: Vec<VecDeque<(J::Key, J::Value)>>,
+    // Records stored on each lender pid and the store ops.
+    reducer_remote: Vec<BTreeMap<usize, Stored<J>>>,
+    // Records spilled to memory and the spill ops.
+    reducer_spill: Vec<Stored<J>>,
+    // Last op occupying each mapper / reducer buffer.
+    mapper_last_op: Vec<Option<usize>>,
+    reducer_last_op: Vec<Option<usize>>,
+    // Shuffle ops delivered to each reducer since its last reduce.
+    reducer_deps: Vec<Vec<usize>>,
+    // Shuffled records and bytes per reducer.
+    reducer_records: Vec<usize>,
+    reducer_bytes: Vec<usize>,
+    reducer_remote_bytes: Vec<usize>,
+    reducer_spilled: Vec<usize>,
+}
+
+impl<J: MapReduceJob> MapReduce<J> {
+    pub fn new(engine: Engine, job: J) -> MapReduce<J> {
+        let mapper_num = engine.mapper_num;
+        let reducer_num = engine.reducer_num;
+        MapReduce {
+            engine,
+            job,
+            overflow: Overflow::Spill,
+            amorph_sram: false,
+            mapper_buffer: (0..mapper_num).map(|_| VecDeque::new()).collect(),
+            reducer_buffer: (0..reducer_num).map(|_| VecDeque::new()).collect(),
+            reducer_remote: (0..reducer_num).map(|_| BTreeMap::new()).collect(),
+            reducer_spill: (0..reducer_num).map(|_| (vec![], vec![])).collect(),
+            mapper_last_op: vec![None; mapper_num],
+            reducer_last_op: vec![None; reducer_num],
+            reducer_deps: vec![vec![]; reducer_num],
+            reducer_records: vec![0; reducer_num],
+            reducer_bytes: vec![0; reducer_num],
+            reducer_remote_bytes: vec![0; reducer_num],
+            reducer_spilled: vec![0; reducer_num],
+        }
+    }
+
+    // Every mapper lends up to `mapper_spare` records of its sram and keeps the
+    // rest, full reducers borrow on demand and keep it for the rest of the job.
+    pub fn set_amorph_sram(&mut self, mapper_spare: usize) {
+        self.amorph_sram = true;
+        for mid in self.engine.mids.clone() {
+            self.engine.reserved[mid] = self.engine.sram_sizes[mid].saturating_sub(mapper_spare);
+        }
+    }
+
+    pub fn amorph_sram(&self) -> bool {
+        self.amorph_sram
+    }
+
+    pub fn set_overflow(&mut self, overflow: Overflow) {
+        self.overflow = overflow;
+    }
+
+    pub fn overflow(&self) -> Overflow {
+        self.overflow
+    }
+
+    pub fn op_list(&self) -> &[Box<dyn OpTrait>] {
+        &self.engine.op_list
+    }
+
+    pub fn load_report(&self) -> LoadReport {
+        LoadReport {
+            policy: self.job.name(),
+            tokens: self.reducer_records.clone(),
+            bytes: self.reducer_bytes.clone(),
+            remote: self.reducer_remote_bytes.clone(),
+            spilled: self.reducer_spilled.clone(),
+        }
+    }
+
+    // Reducer buffer capacity in records, at least one.
+    fn capacity(&self, r_idx: usize) -> usize {
+        self.engine.local_srams[self.engine.rids[r_idx]].max(1)
+    }
+
+    fn records_bytes(&self, records: &[(J::Key, J::Value)]) -> usize {
+        records
+            .iter()
+            .map(|(k, v)| self.job.record_bytes(k, v))
+            .sum()
+    }
+
+    // Load a split into every mapper, returns the bytes loaded.
+    pub fn fill_mapper(&mut self) -> usize {
+        let mut bytes_num = 0;
+        for map_idx in 0..self.engine.mapper_num {
+            let Some((split, bytes)) = self.job.next_split(self.engine.mapper_buf_size) else {
+                break;
+            };
+            if bytes == 0 {
+                break;
+            }
+            bytes_num += bytes;
+            if self.engine.verbose {
+                println!("fill_mapper {}: {} bytes", map_idx, bytes);
+            }
+            // The split waits for the previous split in the mapper to be consumed.
+            let load_op = self.engine.load(
+                self.engine.mids[map_idx],
+                bytes,
+                self.mapper_last_op[map_idx].into_iter().collect(),
+                format!("Load {} bytes from memory to mapper {}", bytes, map_idx),
+            );
+            self.mapper_last_op[map_idx] = Some(load_op);
+            self.mapper_buffer[map_idx].push_back((split, bytes, load_op));
+        }
+        bytes_num
+    }
+
+    pub fn map(&mut self) {
+        let mut valid = true;
+        while valid {
+            valid = false;
+            for map_idx in 0..self.engine.mapper_num {
+                let Some((split, bytes, load_op)) = self.mapper_buffer[map_idx].pop_front() else {
+                    continue;
+                };
+                valid = true;
+                let mid = self.engine.mids[map_idx];
+                let mut bins: BTreeMap<usize, Records<J>> = BTreeMap::new();
+                let mut record_num = 0;
+                for (key, value) in self.job.map(split) {
+                    let r_idx = self.job.partition(&key, self.engine.reducer_num);
+                    bins.entry(r_idx).or_default().push((key, value));
+                    record_num += 1;
+                }
+                if self.engine.verbose {
+                    println!("mapper {}: {:?}", map_idx, &bins);
+                }
+                let mut map_op = self.engine.vec(
+                    mid,
+                    self.job.map_cost(bytes),
+                    vec![load_op],
+                    format!("Mapper {} map {} bytes", map_idx, bytes),
+                );
+                if self.job.use_combiner() {
+                    for records in bins.values_mut() {
+                        let mut combined: BTreeMap<J::Key, J::Value> = BTreeMap::new();
+                        merge(&self.job, &mut combined, records.drain(..));
+                        records.extend(combined);
+                    }
+                    map_op = self.engine.vec(
+                        mid,
+                        self.job.combine_cost(record_num),
+                        vec![map_op],
+                        format!("Mapper {} combine {} records", map_idx, record_num),
+                    );
+                }
+                self.mapper_last_op[map_idx] = Some(map_op);
+                // Send records to corresponding reducers.
+                for (r_idx, records) in bins.into_iter() {
+                    self.shuffle(map_idx, map_op, r_idx, records);
+                }
+            }
+        }
+    }
+
+    // Free record slots of a reducer on each lender.
+    fn remote_free(&self, r_idx: usize) -> Vec<(usize, usize)> {
+        let rid = self.engine.rids[r_idx];
+        let mut held: BTreeMap<usize, usize> = BTreeMap::new();
+        for [lender, size] in self.engine.remote_hold[&rid].iter() {
+            *held.entry(*lender).or_default() += size;
+        }
+        held.into_iter()
+            .map(|(lender, size)| {
+                let used = self.reducer_remote[r_idx]
+                    .get(&lender)
+                    .map(|r| r.0.len())
+                    .unwrap_or(0);
+                (lender, size.saturating_sub(used))
+            })
+            .filter(|(_, free)| *free > 0)
+            .collect()
+    }
+
+    // Store records in the reducer's remote sram, borrowing more if needed.
+    fn store_remote(
+        &mut self,
+        map_idx: usize,
+        map_op: usize,
+        r_idx: usize,
+        records: &mut Records<J>,
+    ) {
+        let mid = self.engine.mids[map_idx];
+        let rid = self.engine.rids[r_idx];
+        let mut slots = self.remote_free(r_idx);
+        let remote_free = slots.iter().map(|(_, free)| free).sum::<usize>();
+        if records.len() > remote_free
+            && self.engine.borrow_sram(rid, records.len() - remote_free) > 0
+        {
+            slots = self.remote_free(r_idx);
+        }
+        for (lender, free) in slots {
+            if records.is_empty() {
+                break;
+            }
+            let stored = records.drain(..free.min(records.len())).collect::<Vec<_>>();
+            let size = self.records_bytes(&stored);
+            let mut deps = vec![map_op];
+            deps.extend(self.reducer_last_op[r_idx]);
+            // Records lent out by the mapper itself stay in place.
+            let store_op = if lender == mid {
+                map_op
+            } else {
+                self.engine.trans_lent(
+                    mid as i32,
+                    lender as i32,
+                    size,
+                    deps,
+                    format!(
+                        "Transfer {} records from mapper {} to remote {} of reducer {}, data size {}",
+                        stored.len(),
+                        map_idx,
+                        lender,
+                        r_idx,
+                        size
+                    ),
+                )
+            };
+            self.reducer_records[r_idx] += stored.len();
+            self.reducer_bytes[r_idx] += size;
+            self.reducer_remote_bytes[r_idx] += size;
+            let remote = self.reducer_remote[r_idx].entry(lender).or_default();
+            remote.0.extend(stored);
+            remote.1.push(store_op);
+        }
+    }
+
+    // Send what fits into the reducer buffer, then its remote sram, then
+    // stall or spill the rest.
+    fn shuffle(&mut self, map_idx: usize, map_op: usize, r_idx: usize, mut records: Records<J>) {
+        let mid = self.engine.mids[map_idx];
+        while !records.is_empty() {
+            let free = self
+                .capacity(r_idx)
+                .saturating_sub(self.reducer_buffer[r_idx].len());
+            if free == 0 && self.amorph_sram {
+                self.store_remote(map_idx, map_op, r_idx, &mut records);
+                if records.is_empty() {
+                    break;
+                }
+            }
+            if free == 0 {
+                match self.overflow {
+                    Overflow::Stall => {
+                        // Later shuffles depend on the reducer's output op.
+                        self.reduce_one(r_idx);
+                        continue;
+                    }
+                    Overflow::Spill => {
+                        let size = self.records_bytes(&records);
+                        let spill_op = self.engine.store(
+                            mid,
+                            size,
+                            vec![map_op],
+                            format!(
+                                "Spill {} records from mapper {} of reducer {} to memory, data size {}",
+                                records.len(),
+                                map_idx,
+                                r_idx,
+                                size
+                            ),
+                        );
+                        self.reducer_records[r_idx] += records.len();
+                        self.reducer_bytes[r_idx] += size;
+                        self.reducer_spilled[r_idx] += size;
+                        let spill = &mut self.reducer_spill[r_idx];
+                        spill.0.append(&mut records);
+                        spill.1.push(spill_op);
+                        break;
+                    }
+                }
+            }
+            let local = records.drain(..free.min(records.len())).collect::<Vec<_>>();
+            let size = self.records_bytes(&local);
+            let mut deps = vec![map_op];
+            deps.extend(self.reducer_last_op[r_idx]);
+            let shuffle_op = self.engine.trans(
+                mid as i32,
+                self.engine.rids[r_idx] as i32,
+                size,
+                deps,
+                format!(
+                    "Shuffle {} records from mapper {} to reducer {}, data size {}",
+                    local.len(),
+                    map_idx,
+                    r_idx,
+                    size
+                ),
+            );
+            self.reducer_deps[r_idx].push(shuffle_op);
+            self.reducer_records[r_idx] += local.len();
+            self.reducer_bytes[r_idx] += size;
+            self.reducer_buffer[r_idx].extend(local);
+        }
+    }
+
+    // Reduce the buffered records, then fetch remote and spilled records
+    // back in buffer-sized batches, and write the merged result to memory.
+    fn reduce_one(&mut self, r_idx: usize) {
+        let remote = std::mem::take(&mut self.reducer_remote[r_idx]);
+        let (spilled, spill_ops) = std::mem::take(&mut self.reducer_spill[r_idx]);
+        if self.reducer_buffer[r_idx].is_empty() && remote.is_empty() && spilled.is_empty() {
+            return;
+        }
+        let rid = self.engine.rids[r_idx];
+        let mut board: BTreeMap<J::Key, J::Value> = BTreeMap::new();
+        let mut reduce_ops = vec![];
+        let mut last_op = self.reducer_last_op[r_idx];
+        if !self.reducer_buffer[r_idx].is_empty() {
+            let record_num = self.reducer_buffer[r_idx].len();
+            let buffer = std::mem::take(&mut self.reducer_buffer[r_idx]);
+            merge(&self.job, &mut board, buffer);
+            let reduce_op = self.engine.vec(
+                rid,
+                self.job.reduce_cost(record_num),
+                std::mem::take(&mut self.reducer_deps[r_idx]),
+                format!("Reducer {} reduce {} records", r_idx, record_num),
+            );
+            reduce_ops.push(reduce_op);
+            last_op = Some(reduce_op);
+        }
+        let capacity = self.capacity(r_idx);
+        for (lender, (records, store_ops)) in remote {
+            for batch in records.chunks(capacity) {
+                let size = self.records_bytes(batch);
+                let mut deps = store_ops.clone();
+                deps.extend(last_op);
+                let fetch_op = self.engine.trans_lent(
+                    lender as i32,
+                    rid as i32,
+                    size,
+                    deps,
+                    format!(
+                        "Reducer {} fetch {} records from remote {}, data size {}",
+                        r_idx,
+                        batch.len(),
+                        lender,
+                        size
+                    ),
+                );
+                let reduce_op = self.engine.vec(
+                    rid,
+                    self.job.reduce_cost(batch.len()),
+                    vec![fetch_op],
+                    format!("Reducer {} reduce {} remote records", r_idx, batch.len()),
+                );
+                merge(&self.job, &mut board, batch.iter().cloned());
+                reduce_ops.push(reduce_op);
+                last_op = Some(reduce_op);
+            }
+        }
+        for batch in spilled.chunks(capacity) {
+            let size = self.records_bytes(batch);
+            let mut deps = spill_ops.clone();
+            deps.extend(last_op);
+            let fetch_op = self.engine.load(
+                rid,
+                size,
+                deps,
+                format!(
+                    "Reducer {} fetch {} spilled records from memory, data size {}",
+                    r_idx,
+                    batch.len(),
+                    size
+                ),
+            );
+            let reduce_op = self.engine.vec(
+                rid,
+                self.job.reduce_cost(batch.len()),
+                vec![fetch_op],
+                format!("Reducer {} reduce {} spilled records", r_idx, batch.len()),
+            );
+            merge(&self.job, &mut board, batch.iter().cloned());
+            reduce_ops.push(reduce_op);
+            last_op = Some(reduce_op);
+        }
+        if self.engine.verbose {
+            println!("reducer {}: {:?}", r_idx, &board);
+        }
+        // Write the partial result back to memory.
+        let size = board
+            .iter()
+            .map(|(k, v)| self.job.output_bytes(k, v))
+            .sum::<usize>();
+        let output_op = self.engine.store(
+            rid,
+            size,
+            reduce_ops,
+            format!(
+                "Reducer {} output {} records, data size {}",
+                r_idx,
+                board.len(),
+                size
+            ),
+        );
+        self.reducer_last_op[r_idx] = Some(output_op);
+        self.job.reduce(r_idx, board);
+    }
+
+    pub fn reduce(&mut self) {
+        for r_idx in 0..self.engine.reducer_num {
+            self.reduce_one(r_idx);
+        }
+    }
+
+    // Fill, map and reduce until the input is exhausted.
+    pub fn run(&mut self) {
+        while self.fill_mapper() > 0 {
+            self.map();
+            self.reduce();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn alloc_under_demand() {
+        let demands = [10, 0, 30];
+        assert_eq!(AllocPolicy::Equal.allocate(90, &demands), vec![30, 30, 30]);
+        assert_eq!(
+            AllocPolicy::DemandCapped.allocate(90, &demands),
+            vec![10, 0, 30]
+        );
+        assert_eq!(
+            AllocPolicy::Proportional.allocate(90, &demands),
+            vec![10, 0, 30]
+        );
+        assert_eq!(
+            AllocPolicy::MinSpill.allocate(90, &demands),
+            vec![10, 0, 30]
+        );
+    }
+
+    #[test]
+    fn alloc_over_demand() {
+        let demands = [10, 20, 60];
+        assert_eq!(AllocPolicy::Equal.allocate(30, &demands), vec![10, 10, 10]);
+        assert_eq!(
+            AllocPolicy::DemandCapped.allocate(30, &demands),
+            vec![10, 10, 10]
+        );
+        assert_eq!(
+            AllocPolicy::Proportional.allocate(30, &demands),
+            vec![3, 6, 20]
+        );
+        // Spill levels off at 30, then at 29 with one more unit.
+        assert_eq!(AllocPolicy::MinSpill.allocate(30, &demands), vec![0, 0, 30]);
+        assert_eq!(AllocPolicy::MinSpill.allocate(31, &demands), vec![0, 0, 31]);
+    }
+
+    #[test]
+    fn alloc_never_exceeds_rentable() {
+        for policy in ["equal", "demand-capped", "proportional", "min-spill"] {
+            let policy = AllocPolicy::from_name(policy).unwrap();
+            for rentable in [0, 1, 7, 100] {
+                let alloc = policy.allocate(rentable, &[5, 9, 3, 40]);
+                assert!(alloc.iter().sum::<usize>() <= rentable, "{:?}", policy);
+            }
+            assert!(policy.allocate(10, &[]).is_empty());
+        }
+    }
+}
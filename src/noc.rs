@@ -0,0 +1,241 @@
+use std::collections::BTreeMap;
+use std::error::Error;
+
+use crate::topology::Mesh;
+
+// Directed link between two routers, the crossbar switch is router `cols * rows`.
+pub type Link = (usize, usize);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NocTopology {
+    Mesh,
+    // Mesh with wrap-around links in both dimensions.
+    Torus,
+    // Every tile has one link into and one link out of a central switch.
+    Crossbar,
+}
+
+impl NocTopology {
+    pub fn from_name(name: &str) -> Result<NocTopology, Box<dyn Error>> {
+        match name {
+            "mesh" => Ok(NocTopology::Mesh),
+            "torus" => Ok(NocTopology::Torus),
+            "crossbar" => Ok(NocTopology::Crossbar),
+            t => Err(format!("Unknown noc topology `{}`", t).into()),
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            NocTopology::Mesh => "mesh",
+            NocTopology::Torus => "torus",
+            NocTopology::Crossbar => "crossbar",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Routing {
+    // Dimension ordered, x first.
+    Xy,
+    // Minimal, every hop takes the productive link that frees up first.
+    Adaptive,
+}
+
+impl Routing {
+    pub fn from_name(name: &str) -> Result<Routing, Box<dyn Error>> {
+        match name {
+            "xy" => Ok(Routing::Xy),
+            "adaptive" => Ok(Routing::Adaptive),
+            r => Err(format!("Unknown routing `{}`", r).into()),
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Routing::Xy => "xy",
+            Routing::Adaptive => "adaptive",
+        }
+    }
+}
+
+// Transfers to and from memory enter through the memory controller and are
+// not routed on the noc.
+#[derive(Debug, Clone)]
+pub struct NocConfig {
+    pub topology: NocTopology,
+    pub routing: Routing,
+    pub cols: usize,
+    pub rows: usize,
+    // Elements per cycle of a link.
+    pub link_width: usize,
+    // Cycles per router traversed.
+    pub router_latency: usize,
+}
+
+impl NocConfig {
+    pub fn new(
+        topology: NocTopology,
+        routing: Routing,
+        mesh: Mesh,
+        link_width: usize,
+        router_latency: usize,
+    ) -> NocConfig {
+        NocConfig {
+            topology,
+            routing,
+            cols: mesh.cols,
+            rows: mesh.rows,
+            link_width,
+            router_latency,
+        }
+    }
+
+    fn switch(&self) -> usize {
+        self.cols * self.rows
+    }
+
+    // Links from src to dst, `busy` is the cycle a link frees up.
+    pub fn route(&self, src: usize, dst: usize, busy: impl Fn(Link) -> usize) -> Vec<Link> {
+        if src == dst {
+            return vec![];
+        }
+        if self.topology == NocTopology::Crossbar {
+            return vec![(src, self.switch()), (self.switch(), dst)];
+        }
+        let mut path = vec![];
+        let mut cur = src;
+        while cur != dst {
+            let candidates = self.productive(cur, dst);
+            let next = match self.routing {
+                Routing::Xy => candidates[0],
+                Routing::Adaptive => *candidates
+                    .iter()
+                    .min_by_key(|next| busy((cur, **next)))
+                    .unwrap(),
+            };
+            path.push((cur, next));
+            cur = next;
+        }
+        path
+    }
+
+    // Neighbours on a minimal path to dst, x direction first.
+    fn productive(&self, cur: usize, dst: usize) -> Vec<usize> {
+        let (cx, cy) = (cur % self.cols, cur / self.cols);
+        let (dx, dy) = (dst % self.cols, dst / self.cols);
+        let mut next = vec![];
+        if cx != dx {
+            next.push(cy * self.cols + self.step(cx, dx, self.cols));
+        }
+        if cy != dy {
+            next.push(self.step(cy, dy, self.rows) * self.cols + cx);
+        }
+        next
+    }
+
+    // One step from c towards d on a line, or ring for torus, of n routers.
+    fn step(&self, c: usize, d: usize, n: usize) -> usize {
+        if self.topology == NocTopology::Torus {
+            let forward = (d + n - c) % n;
+            if forward <= n - forward {
+                (c + 1) % n
+            } else {
+                (c + n - 1) % n
+            }
+        } else if d > c {
+            c + 1
+        } else {
+            c - 1
+        }
+    }
+
+    pub fn latency(&self, length: usize, hops: usize) -> usize {
+        length.div_ceil(self.link_width) + hops * self.router_latency
+    }
+
+    // Busiest outgoing link of every router as a percentage of total cycles.
+    pub fn print_heatmap(&self, link_busy: &BTreeMap<Link, usize>, total_cycles: usize) {
+        let percent = |busy: usize| 100.0 * busy as f64 / total_cycles.max(1) as f64;
+        let mut router_busy = vec![0; self.switch() + 1];
+        for ((from, _), busy) in link_busy.iter() {
+            router_busy[*from] = router_busy[*from].max(*busy);
+        }
+        println!(
+            "Link utilisation ({} {}, busiest outgoing link per router):",
+            self.topology.name(),
+            self.routing.name()
+        );
+        for y in 0..self.rows {
+            let row = (0..self.cols)
+                .map(|x| format!("{:5.1}%", percent(router_busy[y * self.cols + x])))
+                .collect::<Vec<_>>();
+            println!("  {}", row.join(" "));
+        }
+        if self.topology == NocTopology::Crossbar {
+            println!("  switch {:5.1}%", percent(router_busy[self.switch()]));
+        }
+        let mut links = link_busy.iter().collect::<Vec<_>>();
+        links.sort_by_key(|(link, busy)| (std::cmp::Reverse(**busy), **link));
+        for (link, busy) in links.iter().take(4) {
+            println!(
+                "  link {} -> {}: busy {} ({:.1}%)",
+                link.0,
+                link.1,
+                busy,
+                percent(**busy)
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::simulator::MEMORY_ID;
+
+    fn noc(topology: NocTopology, routing: Routing) -> NocConfig {
+        NocConfig::new(topology, routing, Mesh::new(4, 4), 8, 2)
+    }
+
+    #[test]
+    fn mesh_xy() {
+        let path = noc(NocTopology::Mesh, Routing::Xy).route(0, 10, |_| 0);
+        // x first from (0, 0) to (2, 2).
+        assert_eq!(path, vec![(0, 1), (1, 2), (2, 6), (6, 10)]);
+        assert_eq!(path.len(), Mesh::new(4, 4).hops(0, 10));
+        assert!(noc(NocTopology::Mesh, Routing::Xy)
+            .route(5, 5, |_| 0)
+            .is_empty());
+    }
+
+    #[test]
+    fn torus_wraps() {
+        let path = noc(NocTopology::Torus, Routing::Xy).route(0, 3, |_| 0);
+        assert_eq!(path, vec![(0, 3)]);
+        let path = noc(NocTopology::Torus, Routing::Xy).route(0, 15, |_| 0);
+        assert_eq!(path, vec![(0, 3), (3, 15)]);
+    }
+
+    #[test]
+    fn crossbar_through_switch() {
+        let path = noc(NocTopology::Crossbar, Routing::Xy).route(3, 12, |_| 0);
+        assert_eq!(path, vec![(3, 16), (16, 12)]);
+    }
+
+    #[test]
+    fn adaptive_avoids_busy_link() {
+        let busy = |link: Link| if link == (0, 1) { 100 } else { 0 };
+        let path = noc(NocTopology::Mesh, Routing::Adaptive).route(0, 5, busy);
+        assert_eq!(path, vec![(0, 4), (4, 5)]);
+        // Still minimal.
+        assert_eq!(path.len(), Mesh::new(4, 4).hops(0, 5));
+    }
+
+    #[test]
+    fn latency() {
+        let noc = noc(NocTopology::Mesh, Routing::Xy);
+        assert_eq!(noc.latency(17, 3), 3 + 3 * 2);
+        assert_eq!(Mesh::new(4, 4).hops(MEMORY_ID, 5), 0);
+    }
+}
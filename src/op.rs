@@ -1,14 +1,36 @@
 use serde_json::json;
 
+#[allow(clippy::enum_variant_names)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum OpType {
     TransOp,
     VecOp,
     CrossPOp,
+    ReconfOp,
 }
 
 pub trait OpTrait {
     fn format_op(&self) -> String;
     fn dump2json(&self) -> serde_json::Value;
+    fn index(&self) -> usize;
+    fn dependency(&self) -> &[usize];
+    fn op_type(&self) -> OpType;
+    // Module ids touched by the op, memory is -1.
+    fn modules(&self) -> Vec<i32>;
+    // Transferred or computed elements, MACs for cross-product.
+    fn complexity(&self) -> usize;
+    // Mesh hops a transfer crosses.
+    fn hops(&self) -> usize {
+        0
+    }
+    // A transfer storing into or fetching from sram another tile lends.
+    fn lent_sram(&self) -> bool {
+        false
+    }
+    // Elements a compute op reads from and writes to its local sram.
+    fn sram_accesses(&self) -> usize {
+        0
+    }
 }
 
 pub struct TransOp {
@@ -18,6 +40,8 @@ pub struct TransOp {
     src: i32,
     dst: i32,
     length: usize,
+    hops: usize,
+    lent: bool,
     content: String,
 }
 
@@ -37,9 +61,21 @@ impl TransOp {
             src,
             dst,
             length,
+            hops: 0,
+            lent: false,
             content,
         }
     }
+
+    pub fn with_hops(mut self, hops: usize) -> TransOp {
+        self.hops = hops;
+        self
+    }
+
+    pub fn with_lent(mut self, lent: bool) -> TransOp {
+        self.lent = lent;
+        self
+    }
 }
 
 impl OpTrait for TransOp {
@@ -49,18 +85,42 @@ impl OpTrait for TransOp {
     fn dump2json(&self) -> serde_json::Value {
         json!({
             "index": self.idx,
+            "kind": "trans",
             "module": "global",
             "dependency": self.deps,
             "op": {
                 "src": self.src,
                 "dst": self.dst,
                 "len": self.length,
+                "hops": self.hops,
+                "lent": self.lent,
             },
             "op_content": {
                 "name": self.content,
             }
         })
     }
+    fn index(&self) -> usize {
+        self.idx
+    }
+    fn dependency(&self) -> &[usize] {
+        &self.deps
+    }
+    fn op_type(&self) -> OpType {
+        self.op_type
+    }
+    fn modules(&self) -> Vec<i32> {
+        vec![self.src, self.dst]
+    }
+    fn complexity(&self) -> usize {
+        self.length
+    }
+    fn hops(&self) -> usize {
+        self.hops
+    }
+    fn lent_sram(&self) -> bool {
+        self.lent
+    }
 }
 
 pub struct VecOp {
@@ -92,6 +152,7 @@ impl OpTrait for VecOp {
     fn dump2json(&self) -> serde_json::Value {
         json!({
             "index": self.idx,
+            "kind": "elementwise",
             "module": self.pid,
             "dependency": self.deps,
             "op": {
@@ -103,6 +164,25 @@ impl OpTrait for VecOp {
             }
         })
     }
+    fn index(&self) -> usize {
+        self.idx
+    }
+    fn dependency(&self) -> &[usize] {
+        &self.deps
+    }
+    fn op_type(&self) -> OpType {
+        self.op_type
+    }
+    fn modules(&self) -> Vec<i32> {
+        vec![self.pid as i32]
+    }
+    fn complexity(&self) -> usize {
+        self.length
+    }
+    // Two operands in, one result out.
+    fn sram_accesses(&self) -> usize {
+        3 * self.length
+    }
 }
 
 pub struct CrossPOp {
@@ -146,10 +226,11 @@ impl OpTrait for CrossPOp {
     fn dump2json(&self) -> serde_json::Value {
         json!({
             "index": self.idx,
+            "kind": "crossproduct",
             "module": self.pid,
             "dependency": self.deps,
             "op": {
-                "k": self.k, 
+                "k": self.k,
                 "m": self.m,
                 "n": self.n,
                 "complexity": self.k * self.m * self.n,
@@ -160,4 +241,83 @@ impl OpTrait for CrossPOp {
             }
         })
     }
+    fn index(&self) -> usize {
+        self.idx
+    }
+    fn dependency(&self) -> &[usize] {
+        &self.deps
+    }
+    fn op_type(&self) -> OpType {
+        self.op_type
+    }
+    fn modules(&self) -> Vec<i32> {
+        vec![self.pid as i32]
+    }
+    fn complexity(&self) -> usize {
+        self.k * self.m * self.n
+    }
+    // Both operand slices in, the partial sum read and written back.
+    fn sram_accesses(&self) -> usize {
+        self.k * (self.m + self.n) + 2 * self.m * self.n
+    }
+}
+
+// Hands the sram a module lends over to new borrowers.
+pub struct ReconfOp {
+    pub idx: usize,
+    pub deps: Vec<usize>,
+    pub op_type: OpType,
+    pid: usize,
+    // Sram lent out after the reconfiguration.
+    size: usize,
+    content: String,
+}
+
+impl ReconfOp {
+    pub fn new(idx: usize, pid: usize, size: usize, deps: Vec<usize>, content: String) -> ReconfOp {
+        ReconfOp {
+            idx,
+            deps,
+            op_type: OpType::ReconfOp,
+            pid,
+            size,
+            content,
+        }
+    }
+}
+
+impl OpTrait for ReconfOp {
+    fn format_op(&self) -> String {
+        self.content.clone()
+    }
+    fn dump2json(&self) -> serde_json::Value {
+        json!({
+            "index": self.idx,
+            "kind": "reconfig",
+            "module": self.pid,
+            "dependency": self.deps,
+            "op": {
+                "size": self.size,
+                "type": "reconfig",
+            },
+            "op_content": {
+                "name": self.content,
+            }
+        })
+    }
+    fn index(&self) -> usize {
+        self.idx
+    }
+    fn dependency(&self) -> &[usize] {
+        &self.deps
+    }
+    fn op_type(&self) -> OpType {
+        self.op_type
+    }
+    fn modules(&self) -> Vec<i32> {
+        vec![self.pid as i32]
+    }
+    fn complexity(&self) -> usize {
+        self.size
+    }
 }
@@ -1,7 +1,30 @@
-use std::collections::{BTreeMap, HashMap};
+use std::collections::{BTreeMap, BTreeSet};
+use std::error::Error;
 
-use crate::op::{CrossPOp, OpTrait, TransOp, VecOp};
-use crate::util::{closest_factor, Tik};
+use serde_json::json;
+
+use crate::config::{GemmConfig, HardwareConfig};
+use crate::functional::{random_matrix, FunctionalGemm, Place};
+use crate::mapreduce::{AllocPolicy, Engine};
+use crate::op::OpTrait;
+use crate::trace::TraceHeader;
+use crate::util::{balanced_split, closest_factor, XorShift};
+
+// The numbered steps emitted by `OuterProduct::exec`.
+pub const EXEC_STEPS: [&str; 12] = [
+    "mapper load",
+    "mapper cross-product",
+    "map to local",
+    "map to remote",
+    "map to memory",
+    "reducer calc local",
+    "reducer fetch remote",
+    "reducer calc remote",
+    "reducer fetch memory",
+    "reducer calc memory",
+    "reducer output",
+    "reconfigure",
+];
 
 #[derive(Debug)]
 pub struct Slice {
@@ -24,31 +47,137 @@ impl Slice {
     }
 }
 
+// How reducers partition the m x n output.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReducerGrid {
+    // Closest-to-square factorization of reducer_num that fits the output.
+    Square,
+    // para_m x para_n reducers, para_m * para_n should equal reducer_num.
+    Grid(usize, usize),
+    // 1-D split along m.
+    Rows,
+    // 1-D split along n.
+    Cols,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Tiling {
+    Fixed { mapper_k: usize, grid: ReducerGrid },
+    // Search mapper_k and reducer grid minimising bytes spilled to memory.
+    Auto,
+}
+
+impl Default for Tiling {
+    fn default() -> Tiling {
+        Tiling::Fixed {
+            mapper_k: 4,
+            grid: ReducerGrid::Square,
+        }
+    }
+}
+
+// When the remote allocation is recomputed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Realloc {
+    // Once in `set_gemm`.
+    Static,
+    // Before every n-th k-iteration of `exec`, from the mappers active in it and the
+    // outputs reducers still hold.
+    Every(usize),
+}
+
+impl Realloc {
+    pub fn name(&self) -> String {
+        match self {
+            Realloc::Static => "static".to_string(),
+            Realloc::Every(n) => format!("every {}", n),
+        }
+    }
+}
+
+// How each GEMM after the first gets its A operand.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Chain {
+    // Independent GEMMs, A comes from memory.
+    Batch,
+    // A is the previous C, stored to and loaded back from memory.
+    Memory,
+    // A is the previous C, fetched from the reducers that kept it.
+    OnChip,
+}
+
+impl Chain {
+    pub fn from_name(name: &str) -> Result<Chain, Box<dyn Error>> {
+        match name {
+            "batch" => Ok(Chain::Batch),
+            "memory" => Ok(Chain::Memory),
+            "on-chip" => Ok(Chain::OnChip),
+            c => Err(format!("Unknown chain `{}`", c).into()),
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Chain::Batch => "batch",
+            Chain::Memory => "memory",
+            Chain::OnChip => "on-chip",
+        }
+    }
+}
+
+// Output tile of a reducer after `exec`.
+#[derive(Debug, Clone)]
+struct OutputTile {
+    rid: usize,
+    rows: (usize, usize),
+    cols: (usize, usize),
+    // Ops after which the tile is final, its store unless kept on chip.
+    ready: Vec<usize>,
+    on_chip: bool,
+}
+
+impl OutputTile {
+    fn size(&self) -> usize {
+        (self.rows.1 - self.rows.0) * (self.cols.1 - self.cols.0)
+    }
+}
+
+// Emits its cross-product, tile transfer and reduce ops on the shared `Engine`
+// itself, `GemmJob` runs the same GEMM through `MapReduceJob`, see there.
 pub struct OuterProduct {
-    mapper_num: usize,
-    reducer_num: usize,
-    mapper_buf_size: usize,
-    reducer_buf_size: usize,
-    pub op_list: Vec<Box<dyn OpTrait>>,
-    tik: Tik,
-    mids: Vec<usize>,
-    rids: Vec<usize>,
-    mid_ofst: usize,
-    rid_ofst: usize,
+    pub engine: Engine,
+    tile_sram_size: usize,
     amorph_sram: bool,
+    alloc_policy: AllocPolicy,
+    realloc: Realloc,
     mult_array: [usize; 2],
     add_array: [usize; 2],
-    local_srams: Vec<usize>,
-    reducer_remote_sram_size: usize,
-    remote_alloc: BTreeMap<usize, Vec<[usize; 2]>>,
-    remote_hold: BTreeMap<usize, Vec<[usize; 2]>>,
     m: usize,
     k: usize,
     n: usize,
     mapper_workload: Slice,
     reducer_workload: Slice,
-    mapper_unit: Slice,
-    reducer_unit: Slice,
+    tiling: Tiling,
+    // Reducer grid para_m x para_n.
+    para: (usize, usize),
+    // How the current GEMM's A arrives, Batch for the first.
+    input: Chain,
+    // How the GEMMs of the last `exec_chain` pass C on.
+    chain: Chain,
+    // Keep the final output tiles in reducer sram for the next GEMM.
+    keep_output: bool,
+    // Output tiles of the last `exec`.
+    outputs: Vec<OutputTile>,
+    // Every GEMM executed so far.
+    gemms: Vec<[usize; 3]>,
+    // Ops using each lender's lent sram since its last reconfiguration.
+    lender_ops: BTreeMap<usize, Vec<usize>>,
+    functional_seed: Option<u64>,
+    // A and B of the next functional `exec` instead of random ones.
+    functional_operands: Option<(Vec<i64>, Vec<i64>)>,
+    functional: Option<FunctionalGemm>,
+    // Op index -> its `EXEC_STEPS` step (1-based).
+    op_steps: BTreeMap<usize, usize>,
 }
 
 impl OuterProduct {
@@ -60,159 +189,481 @@ impl OuterProduct {
         mult_array: [usize; 2],
         add_array: [usize; 2],
     ) -> OuterProduct {
-        let mid_ofst = 0;
-        let rid_ofst = mapper_num;
-        let midx2pid = (mid_ofst..mid_ofst + mapper_num).collect::<Vec<usize>>();
-        let ridx2pid = (rid_ofst..rid_ofst + reducer_num).collect::<Vec<usize>>();
-        let mut local_srams = vec![tile_sram_size; mapper_num + reducer_num];
-        let mut remote_alloc: BTreeMap<usize, Vec<[usize; 2]>> = BTreeMap::new();
-        let mut remote_hold: BTreeMap<usize, Vec<[usize; 2]>> = BTreeMap::new();
-        let mut reducer_remote_sram_size = 0;
-        // If amorphous, allocate remote sram to reducer.
-        //      Specifically, mapper's comp density is (m*n)/(m+n) mult/element, reducer's comp density is 1 add/element.
-        //      Therefore, we let each mapper & reducer to balance its computation & storage by renting/borrowing from each other.
-        if amorph_sram {
-            // Calc each reducer's remote sram size.
-            let mapper_minimum_sram = mult_array.iter().sum::<usize>();
-            let adder_maximum_sram = add_array.iter().product::<usize>();
-            let rentable_sram = (tile_sram_size - mapper_minimum_sram).max(0) * mapper_num;
-            // let demand_sram = (adder_maximum_sram - tile_sram_size).max(0) * reducer_num;
-            // let remote_sram_size = rentable_sram.min(demand_sram);
-            let remote_sram_size = rentable_sram;
-            reducer_remote_sram_size = remote_sram_size / reducer_num;
-            println!("Reducer remote sram size: {}", reducer_remote_sram_size);
-            // Perform remote allocation.
-            let mut mid = 0;
-            for rid in ridx2pid.iter() {
-                let mut remain_unalloc = reducer_remote_sram_size;
-                while remain_unalloc > 0 {
-                    if local_srams[mid] == 0 {
-                        mid += 1;
-                    }
-                    let alloc_size = local_srams[mid].min(remain_unalloc);
-                    local_srams[mid] -= alloc_size;
-                    remain_unalloc -= alloc_size;
-                    remote_alloc
-                        .entry(mid)
-                        .or_default()
-                        .push([*rid, alloc_size]);
-                    remote_hold.entry(*rid).or_default().push([mid, alloc_size]);
-                    // println!("Remote hold: {:?}", remote_hold);
-                    // println!("Local sram {} size {} alloc {}", mid, local_srams[mid], alloc_size);
-                }
-            }
-        } else {
-            for mid in midx2pid.iter() {
-                remote_alloc.entry(*mid).or_default();
-            }
-            for rid in ridx2pid.iter() {
-                remote_hold.entry(*rid).or_default();
-            }
-        }
-        // Calc the unit computation for mapper and reducer.
-        // Mapper performs a cross-product each
-        let mapper_unit = Slice::new(1, mult_array[0], mult_array[1]);
-        let reducer_unit = Slice::new(2, add_array[0], add_array[1]);
+        let engine = Engine::new(mapper_num, reducer_num, tile_sram_size, tile_sram_size);
 
         OuterProduct {
-            mapper_num,
-            reducer_num,
-            mapper_buf_size: tile_sram_size,
-            reducer_buf_size: tile_sram_size,
-            op_list: vec![],
-            tik: Tik::new(),
-            mids: midx2pid,
-            rids: ridx2pid,
-            mid_ofst,
-            rid_ofst,
+            engine,
+            tile_sram_size,
             amorph_sram,
+            alloc_policy: AllocPolicy::Equal,
+            realloc: Realloc::Static,
             mult_array,
             add_array,
-            local_srams,
-            reducer_remote_sram_size,
-            remote_alloc,
-            remote_hold,
             // Initialize GEMM.
             m: 0,
             k: 0,
             n: 0,
             mapper_workload: Slice::new_empty(),
             reducer_workload: Slice::new_empty(),
-            mapper_unit,
-            reducer_unit,
+            tiling: Tiling::default(),
+            para: (1, 1),
+            input: Chain::Batch,
+            chain: Chain::Batch,
+            keep_output: false,
+            outputs: vec![],
+            gemms: vec![],
+            lender_ops: BTreeMap::new(),
+            functional_seed: None,
+            functional_operands: None,
+            functional: None,
+            op_steps: BTreeMap::new(),
         }
     }
 
-    pub fn set_gemm(&mut self, m: usize, n: usize, k: usize) {
+    // Build from an experiment config, ready to `exec`.
+    pub fn from_config(
+        hw: &HardwareConfig,
+        gemm: &GemmConfig,
+    ) -> Result<OuterProduct, Box<dyn Error>> {
+        let mut outp = OuterProduct::new(
+            hw.mapper_num,
+            hw.reducer_num,
+            hw.tile_sram_size,
+            hw.amorph_sram,
+            hw.mult_array,
+            hw.add_array,
+        );
+        outp.set_alloc_policy(hw.alloc_policy);
+        outp.set_realloc(gemm.realloc);
+        outp.engine.placement = hw.placement;
+        outp.set_tiling(gemm.tiling);
+        outp.set_gemm(gemm.m, gemm.n, gemm.k)?;
+        Ok(outp)
+    }
+
+    pub fn op_list(&self) -> &[Box<dyn OpTrait>] {
+        &self.engine.op_list
+    }
+
+    // Takes effect on the next `set_gemm`.
+    pub fn set_tiling(&mut self, tiling: Tiling) {
+        self.tiling = tiling;
+    }
+
+    // Takes effect on the next `set_gemm`.
+    pub fn set_alloc_policy(&mut self, alloc_policy: AllocPolicy) {
+        self.alloc_policy = alloc_policy;
+    }
+
+    pub fn set_realloc(&mut self, realloc: Realloc) {
+        self.realloc = realloc;
+    }
+
+    pub fn set_gemm(&mut self, m: usize, n: usize, k: usize) -> Result<(), Box<dyn Error>> {
+        if m == 0 || n == 0 || k == 0 {
+            return Err(format!("GEMM {} x {} x {} has an empty dimension", m, n, k).into());
+        }
+        if self.engine.mapper_num == 0 || self.engine.reducer_num == 0 {
+            return Err("Outer-product needs at least one mapper and one reducer".into());
+        }
         self.m = m;
         self.k = k;
         self.n = n;
         // Perform gemm division onto mapper & reducer.
         // Generally, mappers divide on the k dim, reducers divide on the x/y dim.
-        // Currently restrict k > mapper num and m*n > reducer num.
-        assert!(
-            self.k >= self.mapper_num && self.m * self.n > self.reducer_num,
-            "K dim should be larger-equal than mapper num."
-        );
+        // Mappers without a k slice idle, the last slice and edge reducer tiles are smaller.
         // mapper_k controls the granularity of execution. Each mapper_k are reduced by mapper,
         // The reduced fmap is further sent to reducer for reduction.
-        // let mapper_k = (self.k + self.mapper_num - 1) / self.mapper_num;
-        let mapper_k = 4;
+        let (mapper_k, para_m, para_n) = match self.tiling {
+            Tiling::Fixed { mapper_k, grid } => {
+                let (para_m, para_n) = self.reducer_grid(grid);
+                (mapper_k, para_m, para_n)
+            }
+            Tiling::Auto => self.auto_tiling().ok_or(format!(
+                "No reducer grid of {} reducers fits a {} x {} output",
+                self.engine.reducer_num, m, n
+            ))?,
+        };
+        if mapper_k == 0 {
+            return Err("mapper_k should be positive".into());
+        }
+        if para_m * para_n != self.engine.reducer_num {
+            return Err(format!(
+                "Reducer grid {} x {} does not match reducer num {}",
+                para_m, para_n, self.engine.reducer_num
+            )
+            .into());
+        }
+        if !self.grid_fits(para_m, para_n) {
+            return Err(format!(
+                "Reducer grid {} x {} leaves reducers without output on a {} x {} output",
+                para_m, para_n, m, n
+            )
+            .into());
+        }
         self.mapper_workload = Slice::new(mapper_k, self.m, self.n);
-        let para_m = closest_factor(self.reducer_num, (self.reducer_num as f32).sqrt() as usize);
-        let para_n = self.reducer_num / para_m;
-        let reducer_m = (self.m + para_m - 1) / para_m;
-        let reducer_n = (self.n + para_n - 1) / para_n;
-        self.reducer_workload = Slice::new(self.mapper_num, reducer_m, reducer_n);
+        let reducer_m = self.m.div_ceil(para_m);
+        let reducer_n = self.n.div_ceil(para_n);
+        self.reducer_workload = Slice::new(self.engine.mapper_num, reducer_m, reducer_n);
+        self.para = (para_m, para_n);
+        // Re-allocating GEMMs after the first reconfigure from the allocation they inherit.
+        if self.realloc == Realloc::Static || self.gemms.is_empty() {
+            self.allocate_remote(self.active_mappers(0), &BTreeMap::new());
+        }
+        Ok(())
+    }
+
+    // mapper_k and reducer grid of the current GEMM.
+    pub fn tiling(&self) -> (usize, (usize, usize)) {
+        (self.mapper_workload.k, self.para)
+    }
+
+    // Tiling and remote allocation of the current GEMM.
+    pub fn print_schedule(&self) {
+        let (para_m, para_n) = self.para;
+        if self.tiling == Tiling::Auto {
+            println!(
+                "auto tiling: mapper_k {}, reducer grid {} x {}, spilled {}",
+                self.mapper_workload.k,
+                para_m,
+                para_n,
+                self.spilled_bytes(self.mapper_workload.k, para_m, para_n)
+            );
+        }
+        println!("reducer grid: {} x {}", para_m, para_n);
         println!("mapper_workload: {:?}", &self.mapper_workload);
         println!("reducer_workload: {:?}", &self.reducer_workload);
+        if self.amorph_sram {
+            println!("Remote allocation ({}):", self.alloc_policy.name());
+            self.engine.print_remote_alloc();
+        }
+    }
+
+    // Every reducer of the grid owns at least one output element.
+    fn grid_fits(&self, para_m: usize, para_n: usize) -> bool {
+        (1..=self.m).contains(&para_m) && (1..=self.n).contains(&para_n)
+    }
+
+    // Output rows and cols of reducer r_idx, tiles along each dim differ by at most 1.
+    fn tile_bounds(&self, r_idx: usize, para_m: usize, para_n: usize) -> [(usize, usize); 2] {
+        [
+            balanced_split(self.m, para_m, r_idx / para_n),
+            balanced_split(self.n, para_n, r_idx % para_n),
+        ]
+    }
+
+    fn output_tile(&self, r_idx: usize, ready: Vec<usize>, on_chip: bool) -> OutputTile {
+        let (para_m, para_n) = self.para;
+        let [rows, cols] = self.tile_bounds(r_idx, para_m, para_n);
+        OutputTile {
+            rid: self.engine.rids[r_idx],
+            rows,
+            cols,
+            ready,
+            on_chip,
+        }
+    }
+
+    // Mapper's comp density is (m*n)/(m+n) mult/element, reducer's comp density is 1 add/element.
+    // Therefore, if amorphous, active mappers keep the sram of one unit cross-product and lend
+    // the rest to reducers, idle mappers lend all of it.
+    fn rentable_sram(&self, active: usize) -> usize {
+        if !self.amorph_sram {
+            return 0;
+        }
+        let mapper_minimum_sram = self.mult_array.iter().sum::<usize>();
+        let idle = self.engine.mapper_num - active;
+        self.tile_sram_size.saturating_sub(mapper_minimum_sram) * active
+            + self.tile_sram_size * idle
+    }
+
+    // Output elements of each reducer.
+    fn reducer_tiles(&self, para_m: usize, para_n: usize) -> Vec<usize> {
+        (0..self.engine.reducer_num)
+            .map(|r| {
+                let [rows, cols] = self.tile_bounds(r, para_m, para_n);
+                (rows.1 - rows.0) * (cols.1 - cols.0)
+            })
+            .collect()
+    }
+
+    // Partial sums each reducer cannot keep locally when `active` mappers send it a tile,
+    // next to the `resident` outputs it still holds.
+    fn demands(
+        &self,
+        para_m: usize,
+        para_n: usize,
+        active: usize,
+        resident: &BTreeMap<usize, usize>,
+    ) -> Vec<usize> {
+        self.reducer_tiles(para_m, para_n)
+            .iter()
+            .zip(self.engine.rids.iter())
+            .map(|(tile, rid)| {
+                let held = resident.get(rid).copied().unwrap_or(0);
+                (active * tile).saturating_sub(self.tile_sram_size.saturating_sub(held))
+            })
+            .collect()
+    }
+
+    // Remote sram of each reducer under the allocation policy.
+    fn remote_sizes(&self, demands: &[usize], active: usize) -> Vec<usize> {
+        self.alloc_policy
+            .allocate(self.rentable_sram(active), demands)
+    }
+
+    fn allocate_remote(&mut self, active: usize, resident: &BTreeMap<usize, usize>) {
+        self.engine.release_sram();
+        if !self.amorph_sram {
+            return;
+        }
+        let (para_m, para_n) = self.para;
+        let demands = self.demands(para_m, para_n, active, resident);
+        let sizes = self.remote_sizes(&demands, active);
+        self.engine.lend_sram(&sizes);
+    }
+
+    // Output tiles of the previous GEMM each reducer still holds in the iteration at
+    // k_ofst, a tile is freed once earlier iterations fetched all its A columns.
+    fn resident(&self, prev: &[OutputTile], k_ofst: usize) -> BTreeMap<usize, usize> {
+        let mut resident = BTreeMap::new();
+        if self.input != Chain::OnChip {
+            return resident;
+        }
+        for tile in prev.iter().filter(|t| t.on_chip && t.cols.1 > k_ofst) {
+            *resident.entry(tile.rid).or_default() += tile.size();
+        }
+        resident
+    }
+
+    // Mappers with a k slice in the iteration starting at k_ofst.
+    fn active_mappers(&self, k_ofst: usize) -> usize {
+        self.engine
+            .mids
+            .iter()
+            .filter(|mid| k_ofst + *mid * self.mapper_workload.k < self.k)
+            .count()
+    }
+
+    fn reducer_grid(&self, grid: ReducerGrid) -> (usize, usize) {
+        match grid {
+            ReducerGrid::Square => {
+                let reducer_num = self.engine.reducer_num;
+                let para_m = closest_factor(reducer_num, (reducer_num as f32).sqrt() as usize);
+                if self.grid_fits(para_m, reducer_num / para_m) {
+                    return (para_m, reducer_num / para_m);
+                }
+                // Too few rows or cols for it, take the squarest grid that fits.
+                (1..=reducer_num)
+                    .filter(|f| reducer_num.is_multiple_of(*f))
+                    .map(|f| (f, reducer_num / f))
+                    .filter(|(m, n)| self.grid_fits(*m, *n))
+                    .min_by_key(|(m, n)| m.abs_diff(*n))
+                    .unwrap_or((para_m, reducer_num / para_m))
+            }
+            ReducerGrid::Grid(para_m, para_n) => (para_m, para_n),
+            ReducerGrid::Rows => (self.engine.reducer_num, 1),
+            ReducerGrid::Cols => (1, self.engine.reducer_num),
+        }
+    }
+
+    // Bytes sent to memory by exec's step 5 under the given tiling and a static allocation.
+    fn spilled_bytes(&self, mapper_k: usize, para_m: usize, para_n: usize) -> usize {
+        let tiles = self.reducer_tiles(para_m, para_n);
+        let active = self.engine.mapper_num;
+        let demands = self.demands(para_m, para_n, active, &BTreeMap::new());
+        let remote = self.remote_sizes(&demands, active);
+        (0..self.k)
+            .step_by(mapper_k * self.engine.mapper_num)
+            .map(|k_ofst| {
+                let active = (self.k - k_ofst)
+                    .div_ceil(mapper_k)
+                    .min(self.engine.mapper_num);
+                tiles
+                    .iter()
+                    .zip(remote.iter())
+                    .map(|(tile, remote)| {
+                        (active * tile).saturating_sub(self.tile_sram_size + remote)
+                    })
+                    .sum::<usize>()
+            })
+            .sum()
+    }
+
+    // Mappers buffer mapper_k unit vectors of (mult_array[0] + mult_array[1]) elements,
+    // candidate mapper_k are powers of 2 fitting every mapper's local sram.
+    // Ties are broken by finer mapper_k, then squarer reducer grid.
+    fn auto_tiling(&self) -> Option<(usize, usize, usize)> {
+        let unit = self.mult_array.iter().sum::<usize>();
+        let mapper_sram = self.tile_sram_size
+            - self.rentable_sram(self.engine.mapper_num) / self.engine.mapper_num;
+        let max_k = (self.k / self.engine.mapper_num).max(1);
+        let mut best: Option<(usize, usize, usize, usize)> = None;
+        let mut mapper_k = 1;
+        while mapper_k <= max_k {
+            if mapper_k > 1 && mapper_k * unit > mapper_sram {
+                break;
+            }
+            for para_m in
+                (1..=self.engine.reducer_num).filter(|f| self.engine.reducer_num.is_multiple_of(*f))
+            {
+                let para_n = self.engine.reducer_num / para_m;
+                if !self.grid_fits(para_m, para_n) {
+                    continue;
+                }
+                let spill = self.spilled_bytes(mapper_k, para_m, para_n);
+                let better = match best {
+                    None => true,
+                    Some((b_spill, b_k, b_m, b_n)) => {
+                        spill < b_spill
+                            || (spill == b_spill
+                                && mapper_k == b_k
+                                && para_m.abs_diff(para_n) < b_m.abs_diff(b_n))
+                    }
+                };
+                if better {
+                    best = Some((spill, mapper_k, para_m, para_n));
+                }
+            }
+            mapper_k *= 2;
+        }
+        let (_, mapper_k, para_m, para_n) = best?;
+        Some((mapper_k, para_m, para_n))
+    }
+
+    pub fn trace_header(&self) -> TraceHeader {
+        self.engine.trace_header(json!({
+            "workload": "outer-product",
+            "mapper_num": self.engine.mapper_num,
+            "reducer_num": self.engine.reducer_num,
+            "tile_sram_size": self.tile_sram_size,
+            "amorph_sram": self.amorph_sram,
+            "alloc_policy": self.alloc_policy.name(),
+            "realloc": self.realloc.name(),
+            "remote_alloc": self.engine.remote_alloc,
+            "mult_array": self.mult_array,
+            "add_array": self.add_array,
+            "m": self.m,
+            "n": self.n,
+            "k": self.k,
+            "gemms": self.gemms,
+            "chain": self.chain.name(),
+        }))
     }
 
     pub fn exec(&mut self) {
+        self.gemms.push([self.m, self.n, self.k]);
+        if let Some((a, b)) = self.functional_operands.take() {
+            self.functional = Some(FunctionalGemm::with_operands(
+                self.m, self.n, self.k, self.para, a, b,
+            ));
+        } else if let Some(seed) = self.functional_seed {
+            self.functional = Some(FunctionalGemm::new(self.m, self.n, self.k, self.para, seed));
+        }
+        let prev = std::mem::take(&mut self.outputs);
         let mut map_output_ops: Vec<usize> = vec![];
         let mut reduce_output_ops: Vec<usize> = vec![];
-        let mut map2red_local_ops: BTreeMap<usize, (Vec<usize>, usize)> = BTreeMap::new();
-        let mut map2red_remote_ops: BTreeMap<usize, Vec<[usize; 3]>> = BTreeMap::new();
-        let mut map2red_memory_ops: BTreeMap<usize, (Vec<usize>, usize)> = BTreeMap::new();
-        for k_ofst in (0..self.k).step_by(self.mapper_workload.k*self.mapper_num) {
+        let mids = self.engine.mids.clone();
+        let rids = self.engine.rids.clone();
+        // Reconfiguration the next stores into a lender wait for.
+        let mut lender_ready: BTreeMap<usize, usize> = BTreeMap::new();
+        let step = self.mapper_workload.k * self.engine.mapper_num;
+        let (para_m, para_n) = self.para;
+        let tiles = self.reducer_tiles(para_m, para_n);
+        for (iter, k_ofst) in (0..self.k).step_by(step).enumerate() {
+            // The next stores wait for the last reduce, which waits for every fetch of
+            // the consumed tiles, so their sram is free again.
+            let resident = self.resident(&prev, k_ofst);
+            if let Realloc::Every(period) = self.realloc {
+                if iter % period.max(1) == 0 {
+                    lender_ready = self.reallocate(iter, k_ofst, &resident);
+                }
+            }
+            // Partial sums of this iteration waiting at each reducer.
+            let mut map2red_local_ops: BTreeMap<usize, (Vec<usize>, usize)> = BTreeMap::new();
+            let mut map2red_remote_ops: BTreeMap<usize, Vec<[usize; 3]>> = BTreeMap::new();
+            let mut map2red_memory_ops: BTreeMap<usize, (Vec<usize>, usize)> = BTreeMap::new();
+            // Sram left for partial sums this iteration, shared by all mappers.
+            let mut local_free = rids
+                .iter()
+                .map(|rid| {
+                    let held = resident.get(rid).copied().unwrap_or(0);
+                    self.engine.local_srams[*rid].saturating_sub(held)
+                })
+                .collect::<Vec<_>>();
+            let mut remote_used: BTreeMap<usize, Vec<usize>> = BTreeMap::new();
             // Maper operations.
-            for mid in self.mids.iter() {
-                if k_ofst + mid >= self.k {
+            for mid in mids.iter() {
+                let k_lo = k_ofst + mid * self.mapper_workload.k;
+                if k_lo >= self.k {
                     break;
                 }
-                // 1. Mapper fetch a single unit from memory.
-                let trans_op = TransOp::new(
-                    self.tik.tik(),
-                    -1,
-                    *mid as i32,
-                    self.mapper_workload.size(),
-                    map_output_ops.clone(),
-                    format!("{} load map workload of k {} from memory.", mid, k_ofst+mid*self.mapper_workload.k),
+                // The last slice stops at k.
+                let slice = Slice::new(
+                    self.mapper_workload.k.min(self.k - k_lo),
+                    self.mapper_workload.m,
+                    self.mapper_workload.n,
+                );
+                // 1. Mapper fetch a single unit from memory, A columns the previous
+                // GEMM kept on chip come from its reducers.
+                let (fetches, chain_deps) = self.chained_input(&prev, k_lo, k_lo + slice.k);
+                let mut crossp_deps = vec![];
+                let mut on_chip = 0;
+                for (src, size) in fetches {
+                    let mut deps = chain_deps.clone();
+                    deps.extend(map_output_ops.iter());
+                    let fetch_op = self.engine.trans(
+                        src as i32,
+                        *mid as i32,
+                        size,
+                        deps,
+                        format!(
+                            "{} fetch A of k {} on chip from {}, data size {}",
+                            mid, k_lo, src, size
+                        ),
+                    );
+                    self.op_steps.insert(fetch_op, 1);
+                    crossp_deps.push(fetch_op);
+                    on_chip += size;
+                }
+                let mut load_deps = std::mem::take(&mut map_output_ops);
+                load_deps.extend(chain_deps);
+                // The load counts k * m * n workload elements, each A element pairs with
+                // n of them.
+                let trans_op = self.engine.load(
+                    *mid,
+                    slice.size().saturating_sub(on_chip * slice.n),
+                    load_deps,
+                    format!("{} load map workload of k {} from memory.", mid, k_lo),
                 );
-                map_output_ops.clear();
+                self.op_steps.insert(trans_op, 1);
+                crossp_deps.push(trans_op);
                 // 2. Mapper calc m * n.
-                let crossp_op = CrossPOp::new(
-                    self.tik.tik(),
+                let crossp_op_idx = self.engine.crossp(
                     *mid,
-                    self.mapper_workload.k,
-                    self.mapper_workload.m,
-                    self.mapper_workload.n,
-                    vec![trans_op.idx],
+                    [slice.k, slice.m, slice.n],
+                    crossp_deps,
                     format!(
                         "{} performs cross-product of {} x {} with k {}",
-                        mid, self.mapper_workload.m, self.mapper_workload.n, self.mapper_workload.k
+                        mid, slice.m, slice.n, slice.k
                     ),
                 );
-                let crossp_op_idx = crossp_op.idx;
-                self.op_list.push(Box::new(trans_op));
-                self.op_list.push(Box::new(crossp_op));
-                for rid in self.rids.iter() {
+                self.op_steps.insert(crossp_op_idx, 2);
+                if let Some(f) = self.functional.as_mut() {
+                    f.map(*mid, k_lo, k_lo + slice.k);
+                }
+                // Each mapper sends every reducer its own tile of the partial sums.
+                for (r_idx, rid) in rids.iter().enumerate() {
+                    let block_size = tiles[r_idx];
                     let mut deps = vec![crossp_op_idx];
                     // 3. Mapper send results to reducer's local sram.
                     deps.extend(reduce_output_ops.clone());
-                    let to_local_size = self.reducer_workload.size().min(self.local_srams[*rid]);
-                    let map2red_local_op = TransOp::new(
-                        self.tik.tik(),
+                    let to_local_size = block_size.min(local_free[r_idx]);
+                    local_free[r_idx] -= to_local_size;
+                    if let Some(f) = self.functional.as_mut() {
+                        f.send(*mid, r_idx, Place::Local, 0, to_local_size);
+                    }
+                    let map2red_local_op = self.engine.trans(
                         *mid as i32,
                         *rid as i32,
                         to_local_size,
@@ -222,48 +673,81 @@ impl OuterProduct {
                             mid, rid, to_local_size
                         ),
                     );
+                    self.op_steps.insert(map2red_local_op, 3);
                     map2red_local_ops
                         .entry(*rid)
                         .and_modify(|e| {
-                            e.0.push(map2red_local_op.idx);
+                            e.0.push(map2red_local_op);
                             e.1 += to_local_size;
                         })
-                        .or_insert((vec![map2red_local_op.idx], to_local_size));
-                    map_output_ops.push(map2red_local_op.idx);
-                    self.op_list.push(Box::new(map2red_local_op));
+                        .or_insert((vec![map2red_local_op], to_local_size));
+                    map_output_ops.push(map2red_local_op);
                     // 4. Mapper send results to reducer's remote srams.
-                    let mut map_remain_size = self.reducer_workload.size() - to_local_size;
-                    // println!("remote_hold: {:?}", &self.remote_hold);
-                    for remote_sram in self.remote_hold[rid].iter() {
+                    let mut map_remain_size = block_size - to_local_size;
+                    let remote_hold = self.engine.remote_hold[rid].clone();
+                    let used = remote_used
+                        .entry(*rid)
+                        .or_insert_with(|| vec![0; remote_hold.len()]);
+                    for (slot, remote_sram) in remote_hold.iter().enumerate() {
                         if map_remain_size == 0 {
                             break;
                         }
-                        let store_size = map_remain_size.min(remote_sram[1]);
+                        let store_size = map_remain_size.min(remote_sram[1] - used[slot]);
+                        if store_size == 0 {
+                            continue;
+                        }
+                        used[slot] += store_size;
+                        if let Some(f) = self.functional.as_mut() {
+                            let offset = block_size - map_remain_size;
+                            f.send(
+                                *mid,
+                                r_idx,
+                                Place::Remote(remote_sram[0]),
+                                offset,
+                                store_size,
+                            );
+                        }
                         map_remain_size -= store_size;
-                        let map2red_remote_op = TransOp::new(
-                            self.tik.tik(),
+                        // Sram the mapper lends itself already holds the results.
+                        if remote_sram[0] == *mid {
+                            map2red_remote_ops.entry(*rid).or_default().push([
+                                crossp_op_idx,
+                                remote_sram[0],
+                                store_size,
+                            ]);
+                            continue;
+                        }
+                        let mut store_deps = deps.clone();
+                        store_deps.extend(lender_ready.get(&remote_sram[0]));
+                        let map2red_remote_op = self.engine.trans_lent(
                             *mid as i32,
                             remote_sram[0] as i32,
                             store_size,
-                            deps.clone(),
+                            store_deps,
                             format!(
                                 "Transfer from {} to remote {} of {}, data size {}",
                                 mid, remote_sram[0], rid, store_size
                             ),
                         );
+                        self.op_steps.insert(map2red_remote_op, 4);
                         map2red_remote_ops.entry(*rid).or_default().push([
-                            map2red_remote_op.idx,
+                            map2red_remote_op,
                             remote_sram[0],
                             store_size,
                         ]);
-                        map_output_ops.push(map2red_remote_op.idx);
-                        self.op_list.push(Box::new(map2red_remote_op));
+                        map_output_ops.push(map2red_remote_op);
+                        self.lender_ops
+                            .entry(remote_sram[0])
+                            .or_default()
+                            .push(map2red_remote_op);
                     }
                     // 5. Transfer the rest to memory.
-                    let map2red_remote_op = TransOp::new(
-                        self.tik.tik(),
-                        *mid as i32,
-                        -1,
+                    if let Some(f) = self.functional.as_mut() {
+                        let offset = block_size - map_remain_size;
+                        f.send(*mid, r_idx, Place::Memory, offset, map_remain_size);
+                    }
+                    let map2red_memory_op = self.engine.store(
+                        *mid,
                         map_remain_size,
                         deps.clone(),
                         format!(
@@ -271,39 +755,40 @@ impl OuterProduct {
                             mid, -1, map_remain_size
                         ),
                     );
+                    self.op_steps.insert(map2red_memory_op, 5);
                     map2red_memory_ops
                         .entry(*rid)
                         .and_modify(|e| {
-                            e.0.push(map2red_remote_op.idx);
+                            e.0.push(map2red_memory_op);
                             e.1 += map_remain_size;
                         })
-                        .or_insert((vec![map2red_remote_op.idx], map_remain_size));
-                    map_output_ops.push(map2red_remote_op.idx);
-                    self.op_list.push(Box::new(map2red_remote_op));
+                        .or_insert((vec![map2red_memory_op], map_remain_size));
+                    map_output_ops.push(map2red_memory_op);
                 }
             }
             // Reducer operations.
-            for rid in self.rids.iter() {
+            for (r_idx, rid) in rids.iter().enumerate() {
                 let mut output_op_deps = vec![];
                 // 6. Reducer calc local data
                 let local_size = map2red_local_ops[rid].1;
-                let red_calc_local_op = VecOp::new(
-                    self.tik.tik(),
+                if let Some(f) = self.functional.as_mut() {
+                    f.reduce(r_idx, Place::Local, local_size);
+                }
+                let red_calc_local_op = self.engine.vec(
                     *rid,
                     local_size,
                     map2red_local_ops[rid].0.clone(),
                     format!("Reducer {} calc local of size {}", rid, local_size),
                 );
-                output_op_deps.push(red_calc_local_op.idx);
-                self.op_list.push(Box::new(red_calc_local_op));
+                self.op_steps.insert(red_calc_local_op, 6);
+                output_op_deps.push(red_calc_local_op);
                 if map2red_remote_ops.contains_key(rid) {
                     for remote_data in map2red_remote_ops[rid].iter() {
                         // 7. Reducer fetch remote sram
                         let deps = vec![remote_data[0]];
                         let srcid = remote_data[1];
                         let remote_size = remote_data[2];
-                        let red_fetch_remote_op = TransOp::new(
-                            self.tik.tik(),
+                        let red_fetch_remote_op = self.engine.trans_lent(
                             srcid as i32,
                             *rid as i32,
                             remote_size,
@@ -313,56 +798,306 @@ impl OuterProduct {
                                 rid, srcid, remote_size
                             ),
                         );
+                        self.op_steps.insert(red_fetch_remote_op, 7);
                         // 8. Reducer calc remote data
-                        let red_remote_calc_op = VecOp::new(
-                            self.tik.tik(),
+                        let red_remote_calc_op = self.engine.vec(
                             *rid,
                             remote_size,
-                            vec![red_fetch_remote_op.idx],
+                            vec![red_fetch_remote_op],
                             format!("Reducer {} calc size {}", rid, remote_size),
                         );
-                        output_op_deps.push(red_remote_calc_op.idx);
-                        self.op_list.push(Box::new(red_fetch_remote_op));
-                        self.op_list.push(Box::new(red_remote_calc_op));
+                        self.op_steps.insert(red_remote_calc_op, 8);
+                        output_op_deps.push(red_remote_calc_op);
+                        if let Some(f) = self.functional.as_mut() {
+                            f.reduce(r_idx, Place::Remote(srcid), remote_size);
+                        }
+                        self.lender_ops
+                            .entry(srcid)
+                            .or_default()
+                            .push(red_fetch_remote_op);
                     }
                 }
                 // 9. Reducer fetch from memory
                 if map2red_memory_ops.contains_key(rid) {
                     let deps = map2red_memory_ops[rid].0.clone();
                     let mem_size = map2red_memory_ops[rid].1;
-                    let red_fetch_mem_op = TransOp::new(
-                        self.tik.tik(),
-                        -1,
-                        *rid as i32,
+                    let red_fetch_mem_op = self.engine.load(
+                        *rid,
                         mem_size,
                         deps,
                         format!("Reducer {} fetch from memory of size {}", rid, mem_size),
                     );
+                    self.op_steps.insert(red_fetch_mem_op, 9);
                     // 10. Reducer calc memory data
-                    let red_mem_calc_op = VecOp::new(
-                        self.tik.tik(),
+                    let red_mem_calc_op = self.engine.vec(
                         *rid,
                         mem_size,
-                        vec![red_fetch_mem_op.idx],
+                        vec![red_fetch_mem_op],
                         format!("Reducer {} calc size {}", rid, mem_size),
                     );
-                    output_op_deps.push(red_mem_calc_op.idx);
-                    self.op_list.push(Box::new(red_fetch_mem_op));
-                    self.op_list.push(Box::new(red_mem_calc_op));
+                    self.op_steps.insert(red_mem_calc_op, 10);
+                    output_op_deps.push(red_mem_calc_op);
+                    if let Some(f) = self.functional.as_mut() {
+                        f.reduce(r_idx, Place::Memory, mem_size);
+                    }
                 }
-                // 11. Reducer output data.
-                let output_size = self.reducer_workload.m * self.reducer_workload.n;
-                let red_output_op = TransOp::new(
-                    self.tik.tik(),
-                    *rid as i32,
-                    -1,
-                    output_size,
-                    output_op_deps,
-                    format!("Reducer {} output of size {}", rid, output_size),
-                );
-                reduce_output_ops.push(red_output_op.idx);
-                self.op_list.push(Box::new(red_output_op));
+                // 11. Reducer output data, the final tile may stay for the next GEMM.
+                let output_size = tiles[r_idx];
+                let last = k_ofst + step >= self.k;
+                if last && self.keep_output && output_size <= self.engine.local_srams[*rid] {
+                    self.outputs
+                        .push(self.output_tile(r_idx, output_op_deps, true));
+                } else {
+                    let red_output_op = self.engine.store(
+                        *rid,
+                        output_size,
+                        output_op_deps,
+                        format!("Reducer {} output of size {}", rid, output_size),
+                    );
+                    self.op_steps.insert(red_output_op, 11);
+                    reduce_output_ops.push(red_output_op);
+                    if last {
+                        self.outputs
+                            .push(self.output_tile(r_idx, vec![red_output_op], false));
+                    }
+                }
+                if let Some(f) = self.functional.as_mut() {
+                    f.output(r_idx);
+                }
+            }
+            if let Some(f) = self.functional.as_mut() {
+                f.end_iteration(iter);
+            }
+        }
+    }
+
+    // Step of an op emitted by `exec`.
+    pub fn exec_step(&self, op: &dyn OpTrait) -> Option<usize> {
+        self.op_steps.get(&op.index()).copied()
+    }
+
+    // Carry real matrices through every following `exec`.
+    pub fn set_functional(&mut self, seed: u64) {
+        self.functional_seed = Some(seed);
+    }
+
+    // Carry the given m x k A and k x n B through the next `exec`.
+    pub fn set_functional_operands(&mut self, a: Vec<i64>, b: Vec<i64>) {
+        self.functional_operands = Some((a, b));
+    }
+
+    // C of the last functional `exec`.
+    pub fn functional_result(&self) -> Option<&[i64]> {
+        self.functional.as_ref().map(|f| f.result())
+    }
+
+    // Compare the C produced by the last functional `exec` against a reference GEMM.
+    pub fn verify(&self) -> Result<(), Box<dyn Error>> {
+        self.functional
+            .as_ref()
+            .ok_or("Functional mode is off, call set_functional before exec")?
+            .verify()
+    }
+
+    // Exec the current GEMM, then every GEMM of `then` into the same trace. Chained
+    // GEMMs take the previous C as A, so each must have the previous m and k equal
+    // to the previous n.
+    pub fn exec_chain(&mut self, then: &[[usize; 3]], chain: Chain) -> Result<(), Box<dyn Error>> {
+        self.input = Chain::Batch;
+        self.chain = chain;
+        for i in 0..=then.len() {
+            if i > 0 {
+                let [m, n, k] = then[i - 1];
+                if chain != Chain::Batch && (m != self.m || k != self.n) {
+                    return Err(format!(
+                        "GEMM {} takes a {} x {} A but GEMM {} produces a {} x {} C",
+                        i,
+                        m,
+                        k,
+                        i - 1,
+                        self.m,
+                        self.n
+                    )
+                    .into());
+                }
+                // A functional chain feeds the previous C in as A.
+                let prev_c = match chain {
+                    Chain::Batch => None,
+                    _ => self.functional_result().map(|c| c.to_vec()),
+                };
+                self.set_gemm(m, n, k)?;
+                self.input = chain;
+                if self.engine.verbose {
+                    self.print_schedule();
+                }
+                if let Some(a) = prev_c {
+                    let seed = self.functional_seed.unwrap_or(1).wrapping_add(i as u64);
+                    let b = random_matrix(k * n, &mut XorShift::new(seed));
+                    self.set_functional_operands(a, b);
+                }
+            }
+            self.keep_output = chain == Chain::OnChip && i < then.len();
+            self.exec();
+            if self.functional.is_some() {
+                self.verify().map_err(|e| format!("GEMM {}: {}", i, e))?;
+            }
+        }
+        self.input = Chain::Batch;
+        self.keep_output = false;
+        Ok(())
+    }
+
+    // Where the mapper with A columns k_lo..k_hi gets them from the previous GEMM's
+    // output: on-chip tiles as (reducer, elements), and the ops it has to wait for.
+    fn chained_input(
+        &self,
+        prev: &[OutputTile],
+        k_lo: usize,
+        k_hi: usize,
+    ) -> (Vec<(usize, usize)>, Vec<usize>) {
+        let mut fetches = vec![];
+        let mut deps = vec![];
+        if self.input == Chain::Batch {
+            return (fetches, deps);
+        }
+        for tile in prev.iter() {
+            let overlap = k_hi.min(tile.cols.1).saturating_sub(k_lo.max(tile.cols.0));
+            if overlap == 0 {
+                continue;
+            }
+            deps.extend(tile.ready.iter());
+            if tile.on_chip {
+                fetches.push((tile.rid, (tile.rows.1 - tile.rows.0) * overlap));
+            }
+        }
+        (fetches, deps)
+    }
+
+    // Recompute the remote allocation for the phase starting at k_ofst from the partial
+    // sums its active mappers send and the outputs reducers still hold. Every lender whose
+    // borrowers change is reconfigured once the ops on its lent sram are done, the others
+    // keep theirs for a later reconfiguration. Remote data is fetched and reduced within
+    // its iteration, so nothing has to migrate.
+    fn reallocate(
+        &mut self,
+        iter: usize,
+        k_ofst: usize,
+        resident: &BTreeMap<usize, usize>,
+    ) -> BTreeMap<usize, usize> {
+        let old = self.engine.remote_alloc.clone();
+        self.allocate_remote(self.active_mappers(k_ofst), resident);
+        let new = self.engine.remote_alloc.clone();
+        // Lenders of either allocation, those that stop lending reconfigure to lend nothing.
+        let lenders = old
+            .iter()
+            .chain(new.iter())
+            .filter(|(_, lent)| !lent.is_empty())
+            .map(|(mid, _)| *mid)
+            .collect::<BTreeSet<_>>();
+        let mut lender_ready = BTreeMap::new();
+        for mid in lenders {
+            let lent = new.get(&mid).cloned().unwrap_or_default();
+            if old.get(&mid).cloned().unwrap_or_default() == lent {
+                continue;
+            }
+            let size = lent.iter().map(|l| l[1]).sum::<usize>();
+            let reconf_op = self.engine.reconf(
+                mid,
+                size,
+                self.lender_ops.remove(&mid).unwrap_or_default(),
+                format!("Reconfigure sram of {} to lend {:?}", mid, lent),
+            );
+            self.op_steps.insert(reconf_op, 12);
+            lender_ready.insert(mid, reconf_op);
+        }
+        if self.engine.verbose && !lender_ready.is_empty() {
+            println!(
+                "Phase {}: remote allocation ({}):",
+                iter,
+                self.alloc_policy.name()
+            );
+            self.engine.print_remote_alloc();
+        }
+        lender_ready
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::op::OpType;
+
+    // A chained GEMM whose reducers free the previous outputs halfway through k.
+    fn realloc_chain() -> OuterProduct {
+        let mut outp = OuterProduct::new(4, 4, 256, true, [4, 4], [8, 4]);
+        outp.set_alloc_policy(AllocPolicy::MinSpill);
+        outp.set_realloc(Realloc::Every(1));
+        outp.set_tiling(Tiling::Fixed {
+            mapper_k: 2,
+            grid: ReducerGrid::Square,
+        });
+        outp.set_gemm(32, 32, 16).unwrap();
+        // exec_chain verifies every GEMM's C.
+        outp.set_functional(5);
+        outp.exec_chain(&[[32, 32, 32]], Chain::OnChip).unwrap();
+        outp
+    }
+
+    fn reconf_ops(outp: &OuterProduct) -> Vec<&dyn OpTrait> {
+        outp.op_list()
+            .iter()
+            .filter(|op| op.op_type() == OpType::ReconfOp)
+            .map(|op| op.as_ref())
+            .collect()
+    }
+
+    #[test]
+    fn realloc_follows_phase_demand() {
+        let outp = realloc_chain();
+        // All mappers stay active, the reducers whose outputs were consumed by the second
+        // half of k need less remote sram.
+        assert_eq!(reconf_ops(&outp).len(), 2);
+        let remote = outp
+            .engine
+            .rids
+            .iter()
+            .map(|rid| outp.engine.remote_size(*rid))
+            .collect::<Vec<_>>();
+        assert!(remote[0] < remote[1] && remote[2] < remote[3]);
+    }
+
+    #[test]
+    fn reconf_waits_for_lent_sram() {
+        let outp = realloc_chain();
+        let ops = outp.op_list();
+        let by_index = ops
+            .iter()
+            .map(|op| (op.index(), op.as_ref()))
+            .collect::<BTreeMap<_, _>>();
+        for reconf in reconf_ops(&outp) {
+            let lender = reconf.modules()[0];
+            let mut ancestors = BTreeSet::new();
+            let mut stack = reconf.dependency().to_vec();
+            while let Some(idx) = stack.pop() {
+                if ancestors.insert(idx) {
+                    stack.extend(by_index[&idx].dependency());
+                }
+            }
+            let remote = ops.iter().filter(|op| {
+                let modules = op.modules();
+                op.index() < reconf.index()
+                    && match outp.exec_step(op.as_ref()) {
+                        Some(4) => modules[1] == lender,
+                        Some(7) => modules[0] == lender,
+                        _ => false,
+                    }
+            });
+            let mut covered = 0;
+            for op in remote {
+                assert!(ancestors.contains(&op.index()), "{}", op.format_op());
+                covered += 1;
             }
+            assert!(covered > 0);
         }
     }
 }
@@ -0,0 +1,232 @@
+use serde_json::{json, Value};
+use std::error::Error;
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+
+use crate::word_count::normalize;
+
+pub trait Partitioner {
+    // Reducer index in 0..reducer_num for a normalized word.
+    fn partition(&self, word: &str, reducer_num: usize) -> usize;
+    fn name(&self) -> String;
+}
+
+// Bins by the last character, skews on English text.
+pub struct LastCharPartitioner;
+
+impl Partitioner for LastCharPartitioner {
+    fn partition(&self, word: &str, reducer_num: usize) -> usize {
+        word.chars().last().map(|c| c as usize).unwrap_or(0) % reducer_num
+    }
+    fn name(&self) -> String {
+        "last-char".to_string()
+    }
+}
+
+// FNV-1a, stable across runs and toolchains.
+pub struct HashPartitioner;
+
+impl Partitioner for HashPartitioner {
+    fn partition(&self, word: &str, reducer_num: usize) -> usize {
+        let mut hash: u64 = 0xcbf29ce484222325;
+        for b in word.bytes() {
+            hash ^= b as u64;
+            hash = hash.wrapping_mul(0x100000001b3);
+        }
+        (hash % reducer_num as u64) as usize
+    }
+    fn name(&self) -> String {
+        "hash".to_string()
+    }
+}
+
+// Lexicographic ranges, split points are quantiles of a sample.
+pub struct RangePartitioner {
+    splits: Vec<String>,
+}
+
+impl RangePartitioner {
+    pub fn from_sample(mut sample: Vec<String>, reducer_num: usize) -> RangePartitioner {
+        sample.sort();
+        let splits = (1..reducer_num)
+            .filter_map(|r| sample.get(r * sample.len() / reducer_num).cloned())
+            .collect();
+        RangePartitioner { splits }
+    }
+
+    // Sample every `stride`-th word of a file.
+    pub fn sample_file(
+        file_path: &str,
+        reducer_num: usize,
+        stride: usize,
+    ) -> Result<RangePartitioner, Box<dyn Error>> {
+        let mut words = vec![];
+        for line in BufReader::new(File::open(file_path)?).lines() {
+            words.extend(line?.split_whitespace().filter_map(normalize));
+        }
+        let sample = words.into_iter().step_by(stride.max(1)).collect();
+        Ok(RangePartitioner::from_sample(sample, reducer_num))
+    }
+}
+
+impl Partitioner for RangePartitioner {
+    fn partition(&self, word: &str, reducer_num: usize) -> usize {
+        let bin = self.splits.partition_point(|s| s.as_str() <= word);
+        bin.min(reducer_num - 1)
+    }
+    fn name(&self) -> String {
+        format!("range{:?}", self.splits)
+    }
+}
+
+// User-supplied policy, `by_name` registers the custom ones.
+pub struct FnPartitioner<F: Fn(&str, usize) -> usize> {
+    name: String,
+    f: F,
+}
+
+impl<F: Fn(&str, usize) -> usize> FnPartitioner<F> {
+    pub fn new(name: &str, f: F) -> FnPartitioner<F> {
+        FnPartitioner {
+            name: name.to_string(),
+            f,
+        }
+    }
+}
+
+impl<F: Fn(&str, usize) -> usize> Partitioner for FnPartitioner<F> {
+    fn partition(&self, word: &str, reducer_num: usize) -> usize {
+        (self.f)(word, reducer_num) % reducer_num
+    }
+    fn name(&self) -> String {
+        self.name.clone()
+    }
+}
+
+// Built-in policies by name, range samples every 4th word of the input. Custom
+// policies are added here as an `FnPartitioner` under a new name.
+pub fn by_name(
+    name: &str,
+    file_path: &str,
+    reducer_num: usize,
+) -> Result<Box<dyn Partitioner>, Box<dyn Error>> {
+    if reducer_num == 0 {
+        return Err("Partitioning needs at least one reducer".into());
+    }
+    match name {
+        "last-char" => Ok(Box::new(LastCharPartitioner)),
+        "hash" => Ok(Box::new(HashPartitioner)),
+        "range" => Ok(Box::new(RangePartitioner::sample_file(
+            file_path,
+            reducer_num,
+            4,
+        )?)),
+        "first-char" => Ok(Box::new(FnPartitioner::new("first-char", |word, _| {
+            word.chars().next().map(|c| c as usize).unwrap_or(0)
+        }))),
+        p => Err(format!("Unknown partitioner `{}`", p).into()),
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct LoadReport {
+    pub policy: String,
+    // Shuffled records per reducer, tokens or combined words.
+    pub tokens: Vec<usize>,
+    pub bytes: Vec<usize>,
+    // Bytes stored in sram borrowed from mappers.
+    pub remote: Vec<usize>,
+    // Bytes spilled to memory because the reducer buffer was full.
+    pub spilled: Vec<usize>,
+}
+
+impl LoadReport {
+    // Max over mean of records, 1.0 is perfectly balanced.
+    pub fn skew(&self) -> f64 {
+        let total = self.tokens.iter().sum::<usize>();
+        if total == 0 {
+            return 1.0;
+        }
+        let mean = total as f64 / self.tokens.len() as f64;
+        *self.tokens.iter().max().unwrap() as f64 / mean
+    }
+
+    pub fn dump2json(&self) -> Value {
+        json!({
+            "policy": self.policy,
+            "records": self.tokens,
+            "bytes": self.bytes,
+            "remote": self.remote,
+            "spilled": self.spilled,
+            "skew": self.skew(),
+        })
+    }
+
+    pub fn print_summary(&self) {
+        println!("Partitioner {}:", self.policy);
+        for (r, (t, b)) in self.tokens.iter().zip(self.bytes.iter()).enumerate() {
+            println!("  reducer {}: {} records, {} bytes", r, t, b);
+        }
+        println!("  shuffle bytes: {}", self.bytes.iter().sum::<usize>());
+        println!("  remote bytes: {}", self.remote.iter().sum::<usize>());
+        println!("  spilled bytes: {}", self.spilled.iter().sum::<usize>());
+        println!("  skew (max / mean records): {:.2}", self.skew());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const INPUT: &str = "article/1.txt";
+
+    #[test]
+    fn last_char() {
+        let p = LastCharPartitioner;
+        assert_eq!(p.partition("a", 4), 'a' as usize % 4);
+        assert_eq!(p.partition("ba", 4), p.partition("a", 4));
+        assert_eq!(p.partition("", 4), 0);
+    }
+
+    #[test]
+    fn hash_in_range_and_stable() {
+        let p = HashPartitioner;
+        for word in ["the", "of", "mapreduce", ""] {
+            assert!(p.partition(word, 5) < 5);
+            assert_eq!(p.partition(word, 5), p.partition(word, 5));
+        }
+        // FNV-1a offset basis of the empty word.
+        assert_eq!(p.partition("", 7), (0xcbf29ce484222325u64 % 7) as usize);
+    }
+
+    #[test]
+    fn range_splits() {
+        let sample = ["a", "b", "c", "d", "e", "f", "g", "h"]
+            .iter()
+            .map(|s| s.to_string())
+            .collect();
+        let p = RangePartitioner::from_sample(sample, 4);
+        assert_eq!(p.splits, vec!["c", "e", "g"]);
+        assert_eq!(p.partition("a", 4), 0);
+        assert_eq!(p.partition("c", 4), 1);
+        assert_eq!(p.partition("f", 4), 2);
+        assert_eq!(p.partition("z", 4), 3);
+        // Fewer reducers than ranges clamp to the last one.
+        assert_eq!(p.partition("z", 2), 1);
+    }
+
+    #[test]
+    fn custom() {
+        let p = by_name("first-char", INPUT, 3).unwrap();
+        assert_eq!(p.name(), "first-char");
+        assert_eq!(p.partition("b", 3), 'b' as usize % 3);
+        assert_eq!(p.partition("", 3), 0);
+    }
+
+    #[test]
+    fn by_name_rejects() {
+        assert!(by_name("hash", INPUT, 4).is_ok());
+        assert!(by_name("random", INPUT, 4).is_err());
+        assert!(by_name("hash", INPUT, 0).is_err());
+    }
+}
@@ -0,0 +1,385 @@
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, BinaryHeap, HashMap};
+use std::error::Error;
+
+use crate::dram::DramConfig;
+use crate::noc::{Link, NocConfig};
+use crate::op::{OpTrait, OpType};
+
+pub const MEMORY_ID: i32 = -1;
+
+#[derive(Debug, Clone)]
+pub struct SimConfig {
+    pub mult_array: [usize; 2],
+    pub add_array: [usize; 2],
+    // Elements per cycle of an on-chip link.
+    pub link_bw: usize,
+    // Elements per cycle of the off-chip memory.
+    pub dram_bw: usize,
+    // Cycles to retarget the sram a module lends.
+    pub reconfig_latency: usize,
+    // Router cycles per mesh hop of an on-chip transfer.
+    pub hop_latency: usize,
+    // Routes on-chip transfers over individual links, which then contend.
+    pub noc: Option<NocConfig>,
+    // Channels, access latency and row buffers of the memory, dram_bw is then per channel.
+    pub dram: Option<DramConfig>,
+}
+
+impl SimConfig {
+    pub fn new(
+        mult_array: [usize; 2],
+        add_array: [usize; 2],
+        link_bw: usize,
+        dram_bw: usize,
+    ) -> SimConfig {
+        SimConfig {
+            mult_array,
+            add_array,
+            link_bw,
+            dram_bw,
+            reconfig_latency: 16,
+            hop_latency: 1,
+            noc: None,
+            dram: None,
+        }
+    }
+
+    // Every throughput latency divides by must be positive.
+    pub fn validate(&self) -> Result<(), Box<dyn Error>> {
+        if self.mult_array.contains(&0) || self.add_array.contains(&0) {
+            return Err("mult_array and add_array dimensions must be positive".into());
+        }
+        if self.link_bw == 0 || self.dram_bw == 0 {
+            return Err("link_bw and dram_bw must be positive".into());
+        }
+        if let Some(dram) = self.dram.as_ref() {
+            dram.validate()?;
+        }
+        Ok(())
+    }
+
+    pub fn latency(&self, op: &dyn OpTrait) -> usize {
+        match op.op_type() {
+            // Multiplier array performs mult_array[0] x mult_array[1] MACs per cycle.
+            OpType::CrossPOp => op
+                .complexity()
+                .div_ceil(self.mult_array.iter().product::<usize>()),
+            // Adder array performs add_array[0] x add_array[1] adds per cycle.
+            OpType::VecOp => op
+                .complexity()
+                .div_ceil(self.add_array.iter().product::<usize>()),
+            OpType::TransOp => {
+                if op.modules().contains(&MEMORY_ID) {
+                    match self.dram.as_ref() {
+                        // Row hit assumed, the simulator tracks open rows.
+                        Some(dram) => {
+                            dram.latency(op.complexity(), dram.row_misses(op.complexity(), true))
+                        }
+                        None => op.complexity().div_ceil(self.dram_bw),
+                    }
+                } else {
+                    op.complexity().div_ceil(self.link_bw) + op.hops() * self.hop_latency
+                }
+            }
+            OpType::ReconfOp => self.reconfig_latency,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+enum Resource {
+    // Compute array of a module.
+    Compute(usize),
+    // NoC port of a module, shared by its incoming and outgoing transfers.
+    Port(usize),
+    Link(Link),
+    Dram,
+    Channel(usize),
+}
+
+fn resources(op: &dyn OpTrait) -> Vec<Resource> {
+    match op.op_type() {
+        OpType::TransOp => op
+            .modules()
+            .iter()
+            .map(|m| {
+                if *m == MEMORY_ID {
+                    Resource::Dram
+                } else {
+                    Resource::Port(*m as usize)
+                }
+            })
+            .collect(),
+        _ => op
+            .modules()
+            .iter()
+            .map(|m| Resource::Compute(*m as usize))
+            .collect(),
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy)]
+pub struct ModuleStat {
+    pub compute: usize,
+    pub transfer: usize,
+}
+
+#[derive(Debug, Default, Clone, Copy)]
+pub struct ChannelStat {
+    pub busy: usize,
+    pub elements: usize,
+    // Rows touched that were already open and rows activated.
+    pub row_hits: usize,
+    pub row_misses: usize,
+}
+
+pub struct SimReport {
+    pub total_cycles: usize,
+    // Module id -> busy cycles, memory is -1.
+    pub module_busy: BTreeMap<i32, ModuleStat>,
+    pub critical_path: Vec<usize>,
+    pub noc: Option<NocConfig>,
+    // Link -> busy cycles, only with a noc.
+    pub link_busy: BTreeMap<Link, usize>,
+    // Cycles transfers waited on links held by other transfers.
+    pub congestion: usize,
+    pub congested_ops: usize,
+    // Per memory channel, only with a dram model.
+    pub channels: Vec<ChannelStat>,
+    // Critical path cycles spent in memory transfers.
+    pub memory_critical: usize,
+}
+
+impl SimReport {
+    pub fn print_summary(&self) {
+        println!("Total cycles: {}", self.total_cycles);
+        for (module, stat) in self.module_busy.iter() {
+            println!(
+                "Module {}: compute busy {} ({:.1}%), transfer busy {} ({:.1}%)",
+                module,
+                stat.compute,
+                100.0 * stat.compute as f64 / self.total_cycles.max(1) as f64,
+                stat.transfer,
+                100.0 * stat.transfer as f64 / self.total_cycles.max(1) as f64,
+            );
+        }
+        println!(
+            "Critical path of {} ops: {:?}",
+            self.critical_path.len(),
+            self.critical_path
+        );
+        if let Some(noc) = self.noc.as_ref() {
+            println!(
+                "Congestion: {} ops waited {} cycles on busy links",
+                self.congested_ops, self.congestion
+            );
+            noc.print_heatmap(&self.link_busy, self.total_cycles);
+        }
+        for (c, stat) in self.channels.iter().enumerate() {
+            println!(
+                "Channel {}: busy {} ({:.1}%), {} elements, rows hit {} missed {}",
+                c,
+                stat.busy,
+                100.0 * stat.busy as f64 / self.total_cycles.max(1) as f64,
+                stat.elements,
+                stat.row_hits,
+                stat.row_misses
+            );
+        }
+        let share = self.memory_critical as f64 / self.total_cycles.max(1) as f64;
+        println!(
+            "Memory transfers take {} of {} critical path cycles ({:.1}%){}",
+            self.memory_critical,
+            self.total_cycles,
+            100.0 * share,
+            if share > 0.5 { ", bandwidth-bound" } else { "" }
+        );
+    }
+}
+
+pub struct Simulator {
+    config: SimConfig,
+}
+
+impl Simulator {
+    pub fn new(config: SimConfig) -> Simulator {
+        Simulator { config }
+    }
+
+    // Discrete-event list scheduling: an op is issued once all deps have finished,
+    // and starts when every resource it occupies is free.
+    pub fn run(&self, op_list: &[Box<dyn OpTrait>]) -> Result<SimReport, Box<dyn Error>> {
+        self.config.validate()?;
+        let mut pos: HashMap<usize, usize> = HashMap::new();
+        for (i, op) in op_list.iter().enumerate() {
+            if pos.insert(op.index(), i).is_some() {
+                return Err(format!("Duplicated op index {}", op.index()).into());
+            }
+        }
+        let mut indegree = vec![0; op_list.len()];
+        let mut succs: Vec<Vec<usize>> = vec![vec![]; op_list.len()];
+        for (i, op) in op_list.iter().enumerate() {
+            for dep in op.dependency() {
+                let dep_pos = pos.get(dep).ok_or(format!(
+                    "Op {} depends on unknown op {}",
+                    op.index(),
+                    dep
+                ))?;
+                succs[*dep_pos].push(i);
+                indegree[i] += 1;
+            }
+        }
+
+        let mut ready_at = vec![0; op_list.len()];
+        let mut heap: BinaryHeap<Reverse<(usize, usize)>> = BinaryHeap::new();
+        for (i, d) in indegree.iter().enumerate() {
+            if *d == 0 {
+                heap.push(Reverse((0, i)));
+            }
+        }
+        // Resource -> (free time, last op on it).
+        let mut res_free: HashMap<Resource, (usize, usize)> = HashMap::new();
+        let mut finish = vec![0; op_list.len()];
+        let mut crit_pred: Vec<Option<usize>> = vec![None; op_list.len()];
+        let mut schedule = BTreeMap::new();
+        let mut module_busy: BTreeMap<i32, ModuleStat> = BTreeMap::new();
+        let mut link_busy = BTreeMap::new();
+        let (mut congestion, mut congested_ops) = (0, 0);
+        let channel_num = self.config.dram.as_ref().map_or(0, |d| d.channels);
+        let mut channels = vec![ChannelStat::default(); channel_num];
+        // Tile whose row each channel has open.
+        let mut open_row: Vec<Option<i32>> = vec![None; channel_num];
+        let mut done = 0;
+        while let Some(Reverse((ready, i))) = heap.pop() {
+            let op = &op_list[i];
+            let mut res = resources(op.as_ref());
+            let modules = op.modules();
+            // Memory transfers go to the channel of their tile instead of a single dram.
+            let channel = match (self.config.dram.as_ref(), op.op_type()) {
+                (Some(dram), OpType::TransOp) if modules.contains(&MEMORY_ID) => {
+                    let pid = modules.iter().find(|m| **m != MEMORY_ID).copied();
+                    let c = dram.channel(pid.unwrap_or(0) as usize);
+                    for r in res.iter_mut() {
+                        if *r == Resource::Dram {
+                            *r = Resource::Channel(c);
+                        }
+                    }
+                    Some((dram, c, pid))
+                }
+                _ => None,
+            };
+            let mut start = ready;
+            let mut res_pred = None;
+            for r in res.iter() {
+                if let Some((free, last)) = res_free.get(r) {
+                    if *free > start {
+                        start = *free;
+                        res_pred = Some(*last);
+                    }
+                }
+            }
+            let mut latency = self.config.latency(op.as_ref());
+            if let Some((dram, c, pid)) = channel {
+                let row_open = pid.is_some() && open_row[c] == pid;
+                let misses = dram.row_misses(op.complexity(), row_open);
+                latency = dram.latency(op.complexity(), misses);
+                open_row[c] = pid;
+                let stat = &mut channels[c];
+                stat.busy += latency;
+                stat.elements += op.complexity();
+                // Both per row touched: the open first row hits, every activated row misses.
+                stat.row_hits += dram.rows(op.complexity()) - misses;
+                stat.row_misses += misses;
+            }
+            if let (Some(noc), OpType::TransOp) = (self.config.noc.as_ref(), op.op_type()) {
+                if !modules.contains(&MEMORY_ID) {
+                    let path = noc.route(modules[0] as usize, modules[1] as usize, |l| {
+                        res_free.get(&Resource::Link(l)).map_or(0, |f| f.0)
+                    });
+                    let uncongested = start;
+                    for l in path.iter() {
+                        if let Some((free, last)) = res_free.get(&Resource::Link(*l)) {
+                            if *free > start {
+                                start = *free;
+                                res_pred = Some(*last);
+                            }
+                        }
+                    }
+                    if start > uncongested {
+                        congestion += start - uncongested;
+                        congested_ops += 1;
+                    }
+                    latency = noc.latency(op.complexity(), path.len());
+                    for l in path.iter() {
+                        *link_busy.entry(*l).or_default() += latency;
+                    }
+                    res.extend(path.into_iter().map(Resource::Link));
+                }
+            }
+            finish[i] = start + latency;
+            crit_pred[i] = match res_pred {
+                Some(p) => Some(p),
+                None => op
+                    .dependency()
+                    .iter()
+                    .map(|d| pos[d])
+                    .max_by_key(|p| finish[*p]),
+            };
+            for r in res.iter() {
+                res_free.insert(*r, (finish[i], i));
+            }
+            for m in op.modules() {
+                let stat = module_busy.entry(m).or_default();
+                match op.op_type() {
+                    OpType::TransOp => stat.transfer += latency,
+                    _ => stat.compute += latency,
+                }
+            }
+            schedule.insert(op.index(), (start, finish[i]));
+            done += 1;
+            for s in succs[i].iter() {
+                ready_at[*s] = ready_at[*s].max(finish[i]);
+                indegree[*s] -= 1;
+                if indegree[*s] == 0 {
+                    heap.push(Reverse((ready_at[*s], *s)));
+                }
+            }
+        }
+        if done != op_list.len() {
+            return Err(format!(
+                "Dependency cycle, only {} of {} ops scheduled",
+                done,
+                op_list.len()
+            )
+            .into());
+        }
+
+        let mut critical_path = vec![];
+        let mut cur = (0..op_list.len()).max_by_key(|i| finish[*i]);
+        let total_cycles = cur.map(|i| finish[i]).unwrap_or(0);
+        let mut memory_critical = 0;
+        while let Some(i) = cur {
+            let op = &op_list[i];
+            critical_path.push(op.index());
+            if op.op_type() == OpType::TransOp && op.modules().contains(&MEMORY_ID) {
+                let (start, end) = schedule[&op.index()];
+                memory_critical += end - start;
+            }
+            cur = crit_pred[i];
+        }
+        critical_path.reverse();
+
+        Ok(SimReport {
+            total_cycles,
+            module_busy,
+            critical_path,
+            noc: self.config.noc.clone(),
+            link_busy,
+            congestion,
+            congested_ops,
+            channels,
+            memory_critical,
+        })
+    }
+}
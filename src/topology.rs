@@ -0,0 +1,62 @@
+use std::error::Error;
+
+use crate::simulator::MEMORY_ID;
+
+// Tiles on a 2-D mesh, pid p sits at (p % cols, p / cols).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Mesh {
+    pub cols: usize,
+    pub rows: usize,
+}
+
+impl Mesh {
+    pub fn new(cols: usize, rows: usize) -> Mesh {
+        Mesh { cols, rows }
+    }
+
+    // Closest-to-square mesh holding tile_num tiles.
+    pub fn fit(tile_num: usize) -> Mesh {
+        let cols = ((tile_num as f64).sqrt().ceil() as usize).max(1);
+        Mesh::new(cols, tile_num.div_ceil(cols).max(1))
+    }
+
+    pub fn coord(&self, pid: usize) -> (usize, usize) {
+        (pid % self.cols, pid / self.cols)
+    }
+
+    // Manhattan distance, memory sits outside the mesh and costs no hops.
+    pub fn hops(&self, src: i32, dst: i32) -> usize {
+        if src == MEMORY_ID || dst == MEMORY_ID {
+            return 0;
+        }
+        let (sx, sy) = self.coord(src as usize);
+        let (dx, dy) = self.coord(dst as usize);
+        sx.abs_diff(dx) + sy.abs_diff(dy)
+    }
+}
+
+// Which mappers a reducer borrows sram from first.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Placement {
+    // In mapper order.
+    Sequential,
+    // Fewest hops first, ties in mapper order.
+    Nearest,
+}
+
+impl Placement {
+    pub fn from_name(name: &str) -> Result<Placement, Box<dyn Error>> {
+        match name {
+            "sequential" => Ok(Placement::Sequential),
+            "nearest" => Ok(Placement::Nearest),
+            p => Err(format!("Unknown placement `{}`", p).into()),
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Placement::Sequential => "sequential",
+            Placement::Nearest => "nearest",
+        }
+    }
+}
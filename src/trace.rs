@@ -0,0 +1,495 @@
+use serde_json::{json, Value};
+use std::collections::{HashMap, HashSet};
+use std::error::Error;
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Write};
+
+use crate::analysis::topo_order;
+use crate::op::{CrossPOp, OpTrait, OpType, ReconfOp, TransOp, VecOp};
+use crate::simulator::MEMORY_ID;
+use crate::util::{field, i32_field, str_field, usize_field};
+
+// Trace schema, version 3:
+// {
+//   "schema_version": 3,
+//   "header": {
+//     "module_count": <modules are numbered 0..module_count>,
+//     "memory_id": -1,
+//     "generator": { "workload": <name>, <constructor & workload parameters>,
+//                    word-count adds "load", its records and bytes per reducer },
+//     "energy": <optional, cost table and totals of a run with --energy>
+//   },
+//   "ops": [ <op>, ... ]
+// }
+// Every op has "index", "kind", "dependency" (indices of ops in the same trace),
+// "module", "op" and "op_content": { "name" }, and optionally "energy" in pJ.
+//   kind "trans":        module is "global", op is { "src", "dst", "len", "hops", "lent" },
+//                        src/dst are module ids or memory_id, hops is optional [0],
+//                        lent marks a store into or fetch from lent sram, optional [false].
+//   kind "elementwise":  module is the executing module id, op is { "complexity", "type" }.
+//   kind "crossproduct": module is the executing module id,
+//                        op is { "k", "m", "n", "complexity" = k*m*n, "type" }.
+//   kind "reconfig":     module is the lending module id, op is { "size", "type" },
+//                        size is the sram it lends after the reconfiguration.
+// Version 3 added kind "reconfig" and the transfer "hops" and "lent", version 2 traces have none
+// and still parse. Version 1 traces are a bare op list without header and "kind".
+pub const SCHEMA_VERSION: u64 = 3;
+
+// Fields an op of the given schema version cannot carry.
+fn version_error(op: &Value, schema_version: u64) -> Option<String> {
+    if schema_version >= 3 {
+        return None;
+    }
+    if op.get("kind").and_then(|k| k.as_str()) == Some("reconfig") {
+        return Some(format!(
+            "kind `reconfig` needs schema version 3, trace is version {}",
+            schema_version
+        ));
+    }
+    for key in ["hops", "lent"] {
+        if op.get("op").and_then(|o| o.get(key)).is_some() {
+            return Some(format!(
+                "transfer `{}` needs schema version 3, trace is version {}",
+                key, schema_version
+            ));
+        }
+    }
+    None
+}
+
+#[derive(Debug, Clone)]
+pub struct TraceHeader {
+    pub schema_version: u64,
+    pub module_count: usize,
+    pub generator: Value,
+}
+
+impl TraceHeader {
+    pub fn new(module_count: usize, generator: Value) -> TraceHeader {
+        TraceHeader {
+            schema_version: SCHEMA_VERSION,
+            module_count,
+            generator,
+        }
+    }
+
+    pub fn dump2json(&self) -> Value {
+        json!({
+            "module_count": self.module_count,
+            "memory_id": MEMORY_ID,
+            "generator": self.generator,
+        })
+    }
+}
+
+pub struct Trace {
+    pub header: TraceHeader,
+    pub ops: Vec<Box<dyn OpTrait>>,
+}
+
+pub fn dump_trace(header: &TraceHeader, op_list: &[Box<dyn OpTrait>]) -> Value {
+    json!({
+        "schema_version": header.schema_version,
+        "header": header.dump2json(),
+        "ops": op_list.iter().map(|op| op.dump2json()).collect::<Vec<_>>(),
+    })
+}
+
+// A dumped trace, e.g. after `EnergyModel::annotate`.
+// Version 1 trace, a bare op list without "kind" and transfer "hops" and "lent".
+pub fn dump_v1(op_list: &[Box<dyn OpTrait>]) -> Result<Value, Box<dyn Error>> {
+    let mut ops = vec![];
+    for op in op_list.iter() {
+        if op.op_type() == OpType::ReconfOp {
+            return Err(format!("Op {}: reconfig has no version 1 form", op.index()).into());
+        }
+        let mut value = op.dump2json();
+        if let Some(value) = value.as_object_mut() {
+            value.remove("kind");
+        }
+        if let Some(op) = value["op"].as_object_mut() {
+            op.remove("hops");
+            op.remove("lent");
+        }
+        ops.push(value);
+    }
+    Ok(Value::Array(ops))
+}
+
+pub fn save_json(path: &str, value: &Value) -> Result<(), Box<dyn Error>> {
+    let file = File::create(path)?;
+    let mut writer = BufWriter::new(file);
+    serde_json::to_writer_pretty(&mut writer, value)?;
+    writer.flush()?;
+    Ok(())
+}
+
+fn read_json(path: &str) -> Result<Value, Box<dyn Error>> {
+    let file = File::open(path)?;
+    Ok(serde_json::from_reader(BufReader::new(file))?)
+}
+
+// Load a trace that passes `validate_trace`.
+pub fn load_trace(path: &str) -> Result<Trace, Box<dyn Error>> {
+    let value = read_json(path)?;
+    let errors = validate_trace(&value);
+    if let Some(first) = errors.first() {
+        return Err(format!(
+            "{}: {} schema violations, first: {}",
+            path,
+            errors.len(),
+            first
+        )
+        .into());
+    }
+    parse_trace(&value)
+}
+
+pub fn parse_trace(value: &Value) -> Result<Trace, Box<dyn Error>> {
+    // Version 1: bare op list, module count is inferred from the ops.
+    if let Some(list) = value.as_array() {
+        let ops = list.iter().map(parse_op).collect::<Result<Vec<_>, _>>()?;
+        let module_count = ops
+            .iter()
+            .flat_map(|op| op.modules())
+            .max()
+            .map(|m| (m + 1).max(0) as usize)
+            .unwrap_or(0);
+        return Ok(Trace {
+            header: TraceHeader {
+                schema_version: 1,
+                module_count,
+                generator: Value::Null,
+            },
+            ops,
+        });
+    }
+    let header = parse_header(value)?;
+    let ops = field(value, "ops")?
+        .as_array()
+        .ok_or("Field `ops` should be a list")?
+        .iter()
+        .map(|op| match version_error(op, header.schema_version) {
+            Some(e) => Err(e.into()),
+            None => parse_op(op),
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    Ok(Trace { header, ops })
+}
+
+fn parse_header(value: &Value) -> Result<TraceHeader, Box<dyn Error>> {
+    let schema_version = field(value, "schema_version")?
+        .as_u64()
+        .ok_or("Field `schema_version` should be an unsigned integer")?;
+    if schema_version > SCHEMA_VERSION {
+        return Err(format!(
+            "Unsupported schema version {}, expected at most {}",
+            schema_version, SCHEMA_VERSION
+        )
+        .into());
+    }
+    let header = field(value, "header")?;
+    if i32_field(header, "memory_id")? != MEMORY_ID {
+        return Err(format!("Field `memory_id` should be {}", MEMORY_ID).into());
+    }
+    Ok(TraceHeader {
+        schema_version,
+        module_count: usize_field(header, "module_count")?,
+        generator: field(header, "generator")?.clone(),
+    })
+}
+
+pub fn parse_op(value: &Value) -> Result<Box<dyn OpTrait>, Box<dyn Error>> {
+    let idx = usize_field(value, "index")?;
+    let deps = field(value, "dependency")?
+        .as_array()
+        .ok_or(format!("Op {}: `dependency` should be a list", idx))?
+        .iter()
+        .map(|d| {
+            d.as_u64()
+                .map(|d| d as usize)
+                .ok_or(format!("Op {}: invalid dependency {}", idx, d))
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    let content = str_field(field(value, "op_content")?, "name")?.to_string();
+    let module = field(value, "module")?;
+    let op = field(value, "op")?;
+    // Version 1 ops carry no kind, transfers are the ops without a type.
+    let kind = match value.get("kind") {
+        Some(kind) => kind
+            .as_str()
+            .ok_or(format!("Op {}: `kind` should be a string", idx))?,
+        None => op.get("type").and_then(|t| t.as_str()).unwrap_or("trans"),
+    };
+    if kind != "trans" && op.get("type").and_then(|t| t.as_str()) != Some(kind) {
+        return Err(format!("Op {}: `op.type` should be \"{}\"", idx, kind).into());
+    }
+
+    match kind {
+        "trans" => {
+            if module.as_str() != Some("global") {
+                return Err(format!("Op {}: transfer module should be \"global\"", idx).into());
+            }
+            let hops = match op.get("hops") {
+                Some(_) => usize_field(op, "hops")?,
+                None => 0,
+            };
+            let lent = match op.get("lent") {
+                Some(lent) => lent
+                    .as_bool()
+                    .ok_or(format!("Op {}: field `lent` should be a boolean", idx))?,
+                None => false,
+            };
+            Ok(Box::new(
+                TransOp::new(
+                    idx,
+                    i32_field(op, "src")?,
+                    i32_field(op, "dst")?,
+                    usize_field(op, "len")?,
+                    deps,
+                    content,
+                )
+                .with_hops(hops)
+                .with_lent(lent),
+            ))
+        }
+        "elementwise" => Ok(Box::new(VecOp::new(
+            idx,
+            usize_field(value, "module")?,
+            usize_field(op, "complexity")?,
+            deps,
+            content,
+        ))),
+        "crossproduct" => {
+            let (k, m, n) = (
+                usize_field(op, "k")?,
+                usize_field(op, "m")?,
+                usize_field(op, "n")?,
+            );
+            if usize_field(op, "complexity")? != k * m * n {
+                return Err(format!("Op {}: complexity should equal k * m * n", idx).into());
+            }
+            Ok(Box::new(CrossPOp::new(
+                idx,
+                usize_field(value, "module")?,
+                k,
+                m,
+                n,
+                deps,
+                content,
+            )))
+        }
+        "reconfig" => Ok(Box::new(ReconfOp::new(
+            idx,
+            usize_field(value, "module")?,
+            usize_field(op, "size")?,
+            deps,
+            content,
+        ))),
+        t => Err(format!("Op {}: unknown op kind `{}`", idx, t).into()),
+    }
+}
+
+// Check a trace against the schema, returns every violation found.
+pub fn validate_trace(value: &Value) -> Vec<String> {
+    let mut errors = vec![];
+    let header = match value.as_array() {
+        Some(_) => None,
+        None => match parse_header(value) {
+            Ok(header) => Some(header),
+            Err(e) => {
+                errors.push(format!("Header: {}", e));
+                None
+            }
+        },
+    };
+    let list = match value
+        .as_array()
+        .or(value.get("ops").and_then(|o| o.as_array()))
+    {
+        Some(list) => list,
+        None => {
+            errors.push("Trace has no op list".to_string());
+            return errors;
+        }
+    };
+    let mut ops = vec![];
+    for (i, op) in list.iter().enumerate() {
+        if header.is_some() && op.get("kind").is_none() {
+            errors.push(format!("Op #{}: missing field `kind`", i));
+        }
+        if let Some(e) = header
+            .as_ref()
+            .and_then(|h| version_error(op, h.schema_version))
+        {
+            errors.push(format!("Op #{}: {}", i, e));
+        }
+        match parse_op(op) {
+            Ok(op) => ops.push(op),
+            Err(e) => errors.push(format!("Op #{}: {}", i, e)),
+        }
+    }
+    errors.extend(validate_ops(&ops, header.map(|h| h.module_count)));
+    errors
+}
+
+// Graph and module checks on parsed ops.
+pub fn validate_ops(op_list: &[Box<dyn OpTrait>], module_count: Option<usize>) -> Vec<String> {
+    let mut errors = vec![];
+    let mut seen: HashMap<usize, usize> = HashMap::new();
+    for op in op_list.iter() {
+        *seen.entry(op.index()).or_default() += 1;
+    }
+    for (idx, cnt) in seen.iter().filter(|(_, c)| **c > 1) {
+        errors.push(format!("Op index {} appears {} times", idx, cnt));
+    }
+    let mut dangling = false;
+    for op in op_list.iter() {
+        let mut deps = HashSet::new();
+        for dep in op.dependency() {
+            if !seen.contains_key(dep) {
+                errors.push(format!("Op {}: dangling dependency {}", op.index(), dep));
+                dangling = true;
+            }
+            if !deps.insert(dep) {
+                errors.push(format!("Op {}: repeated dependency {}", op.index(), dep));
+            }
+        }
+        let modules = op.modules();
+        for m in modules.iter() {
+            let known = match module_count {
+                Some(count) => *m >= 0 && (*m as usize) < count,
+                None => *m >= 0,
+            };
+            let memory = op.op_type() == OpType::TransOp && *m == MEMORY_ID;
+            if !known && !memory {
+                errors.push(format!("Op {}: unknown module id {}", op.index(), m));
+            }
+        }
+        if op.op_type() == OpType::TransOp && modules[0] == modules[1] {
+            errors.push(format!(
+                "Op {}: transfer with identical src and dst {}",
+                op.index(),
+                modules[0]
+            ));
+        }
+    }
+    // Cycles only make sense to report once every dependency resolves.
+    if !dangling && seen.values().all(|c| *c == 1) {
+        if let Err(e) = topo_order(op_list) {
+            errors.push(e.to_string());
+        }
+    }
+    errors
+}
+
+pub fn validate_trace_file(path: &str) -> Result<Vec<String>, Box<dyn Error>> {
+    Ok(validate_trace(&read_json(path)?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures::{reconf, trace_json, trans};
+    use crate::outer_product::OuterProduct;
+
+    // Memory loads into module 0, each after the ops listed.
+    fn loads(deps: &[&[usize]]) -> Vec<Box<dyn OpTrait>> {
+        deps.iter()
+            .enumerate()
+            .map(|(idx, deps)| trans(idx, MEMORY_ID, 0, 16, deps))
+            .collect()
+    }
+
+    #[test]
+    fn valid_trace() {
+        assert!(validate_trace(&trace_json(2, &loads(&[&[], &[0]]))).is_empty());
+    }
+
+    #[test]
+    fn duplicate_index() {
+        let ops = vec![trans(0, MEMORY_ID, 0, 16, &[]), trans(0, 0, 1, 16, &[])];
+        assert_eq!(
+            validate_trace(&trace_json(2, &ops)),
+            vec!["Op index 0 appears 2 times"]
+        );
+    }
+
+    #[test]
+    fn dangling_dependency() {
+        assert_eq!(
+            validate_trace(&trace_json(2, &loads(&[&[], &[5]]))),
+            vec!["Op 1: dangling dependency 5"]
+        );
+    }
+
+    #[test]
+    fn dependency_cycle() {
+        let errors = validate_trace(&trace_json(2, &loads(&[&[2], &[0], &[1]])));
+        assert_eq!(errors.len(), 1);
+        assert!(errors[0].starts_with("Dependency cycle"), "{}", errors[0]);
+    }
+
+    #[test]
+    fn unknown_module() {
+        let ops = vec![trans(0, MEMORY_ID, 3, 16, &[])];
+        assert_eq!(
+            validate_trace(&trace_json(2, &ops)),
+            vec!["Op 0: unknown module id 3"]
+        );
+    }
+
+    #[test]
+    fn version_1_shape() {
+        let ops = loads(&[&[], &[0]]);
+        let value = dump_v1(&ops).unwrap();
+        assert_eq!(value[1]["module"], "global");
+        assert!(value[1].get("kind").is_none());
+        assert!(value[1]["op"].get("hops").is_none());
+        assert!(value[1]["op"].get("lent").is_none());
+        let trace = parse_trace(&value).unwrap();
+        assert_eq!(trace.header.schema_version, 1);
+        assert!(validate_trace(&value).is_empty());
+    }
+
+    #[test]
+    fn version_2_rejects_reconfig() {
+        let ops = vec![trans(0, MEMORY_ID, 0, 16, &[]), reconf(1, 0, 64, &[0])];
+        let mut value = trace_json(2, &ops);
+        assert!(validate_trace(&value).is_empty());
+        value["schema_version"] = json!(2);
+        assert_eq!(validate_trace(&value).len(), 2);
+        assert!(parse_trace(&value).is_err());
+    }
+
+    #[test]
+    fn round_trip() {
+        let mut outp = OuterProduct::new(4, 4, 256, true, [4, 4], [8, 4]);
+        outp.set_gemm(24, 20, 32).unwrap();
+        outp.exec();
+        let value = dump_trace(&outp.trace_header(), outp.op_list());
+        let trace = parse_trace(&value).unwrap();
+        assert_eq!(trace.header.module_count, 8);
+        assert_eq!(trace.ops.len(), outp.op_list().len());
+        for (loaded, op) in trace.ops.iter().zip(outp.op_list().iter()) {
+            assert_eq!(loaded.index(), op.index());
+            assert_eq!(loaded.op_type(), op.op_type());
+            assert_eq!(loaded.modules(), op.modules());
+            assert_eq!(loaded.dependency(), op.dependency());
+            assert_eq!(loaded.complexity(), op.complexity());
+            assert_eq!(loaded.hops(), op.hops());
+            assert_eq!(loaded.lent_sram(), op.lent_sram());
+        }
+    }
+
+    #[test]
+    fn load_validates() {
+        let path = std::env::temp_dir().join("mapreduce-load-validates.json");
+        let path = path.to_str().unwrap();
+        save_json(path, &trace_json(2, &loads(&[&[], &[5]]))).unwrap();
+        let e = load_trace(path).err().unwrap().to_string();
+        assert!(e.contains("dangling dependency 5"), "{}", e);
+        save_json(path, &trace_json(2, &loads(&[&[], &[0]]))).unwrap();
+        assert_eq!(load_trace(path).unwrap().ops.len(), 2);
+    }
+}
@@ -1,3 +1,6 @@
+use serde_json::Value;
+use std::error::Error;
+
 pub struct Tik {
     val: usize,
 }
@@ -12,50 +15,134 @@ impl Tik {
         self.val += 1;
         cur
     }
+}
 
-    pub fn init(&mut self) {
-        self.val = 0;
+// Divisor of value closest to factor, the smaller one on a tie.
+pub fn closest_factor(value: usize, factor: usize) -> usize {
+    let lower = (1..=factor.min(value))
+        .rev()
+        .find(|f| value.is_multiple_of(*f))
+        .unwrap_or(1);
+    let upper = (factor.max(1)..=value)
+        .find(|f| value.is_multiple_of(*f))
+        .unwrap_or(lower);
+    if upper.abs_diff(factor) < lower.abs_diff(factor) {
+        upper
+    } else {
+        lower
     }
 }
 
-pub fn closest_factor(value: usize, factor: usize) -> usize {
-    println!("closest_factor: {} {}", value, factor);
-    let mut res = vec![];
-    if factor >= 1 {
-        let mut f = factor + 1;
-        while f > factor {
-            f -= 1;
-        }
-        loop {
-            if f != 0 && value % f == 0 {
-                break;
-            }
-            f -= 1;
-        }
-        println!("closest_factor: {}", f);
-        res.push(f);
+// Range of part idx when len is split into parts whose sizes differ by at most 1,
+// the first len % parts get the extra element.
+pub fn balanced_split(len: usize, parts: usize, idx: usize) -> (usize, usize) {
+    let (base, extra) = (len / parts, len % parts);
+    let lo = idx * base + idx.min(extra);
+    (lo, lo + base + usize::from(idx < extra))
+}
+
+// Xorshift64* generator, enough for design-space sampling without extra deps.
+pub struct XorShift {
+    state: u64,
+}
+
+impl XorShift {
+    pub fn new(seed: u64) -> XorShift {
+        XorShift { state: seed.max(1) }
     }
 
-    if factor <= value {
-        let mut f = factor - 1;
-        while f < factor {
-            f += 1;
-        }
-        loop {
-            if f != 0 && value % f == 0 {
-                break;
-            }
-            f += 1;
+    pub fn next_u64(&mut self) -> u64 {
+        self.state ^= self.state >> 12;
+        self.state ^= self.state << 25;
+        self.state ^= self.state >> 27;
+        self.state.wrapping_mul(0x2545F4914F6CDD1D)
+    }
+
+    // Uniform in [0, 1).
+    pub fn next_f64(&mut self) -> f64 {
+        (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64
+    }
+
+    pub fn below(&mut self, n: usize) -> usize {
+        (self.next_f64() * n as f64) as usize
+    }
+
+    pub fn shuffle<T>(&mut self, v: &mut [T]) {
+        for i in (1..v.len()).rev() {
+            let j = self.below(i + 1);
+            v.swap(i, j);
         }
-        println!("closest_factor: {}", f);
-        res.push(f);
     }
+}
 
-    let a = res[0] - factor;
-    let b = factor - res[1];
-    if a > b {
-        return res[1];
-    } else {
-        return res[0];
+// Typed field access for the JSON traces and config files.
+pub fn field<'a>(value: &'a Value, key: &str) -> Result<&'a Value, Box<dyn Error>> {
+    value
+        .get(key)
+        .ok_or_else(|| format!("Missing field `{}` in {}", key, value).into())
+}
+
+pub fn usize_field(value: &Value, key: &str) -> Result<usize, Box<dyn Error>> {
+    let v = field(value, key)?;
+    v.as_u64()
+        .map(|v| v as usize)
+        .ok_or_else(|| format!("Field `{}` should be an unsigned integer, got {}", key, v).into())
+}
+
+pub fn f64_field(value: &Value, key: &str) -> Result<f64, Box<dyn Error>> {
+    let v = field(value, key)?;
+    v.as_f64()
+        .ok_or_else(|| format!("Field `{}` should be a number, got {}", key, v).into())
+}
+
+pub fn i32_field(value: &Value, key: &str) -> Result<i32, Box<dyn Error>> {
+    let v = field(value, key)?;
+    v.as_i64()
+        .and_then(|v| i32::try_from(v).ok())
+        .ok_or_else(|| format!("Field `{}` should be an integer, got {}", key, v).into())
+}
+
+pub fn str_field<'a>(value: &'a Value, key: &str) -> Result<&'a str, Box<dyn Error>> {
+    let v = field(value, key)?;
+    v.as_str()
+        .ok_or_else(|| format!("Field `{}` should be a string, got {}", key, v).into())
+}
+
+pub fn bool_field(value: &Value, key: &str) -> Result<bool, Box<dyn Error>> {
+    let v = field(value, key)?;
+    v.as_bool()
+        .ok_or_else(|| format!("Field `{}` should be a boolean, got {}", key, v).into())
+}
+
+// [a, b, ...] of exactly N unsigned integers.
+pub fn usize_array<const N: usize>(v: &Value, key: &str) -> Result<[usize; N], Box<dyn Error>> {
+    let error = || {
+        format!(
+            "Field `{}` should be {} unsigned integers, got {}",
+            key, N, v
+        )
+    };
+    let list = v
+        .as_array()
+        .ok_or_else(error)?
+        .iter()
+        .map(|x| x.as_u64().map(|x| x as usize).ok_or_else(error))
+        .collect::<Result<Vec<_>, _>>()?;
+    Ok(<[usize; N]>::try_from(list).map_err(|_| error())?)
+}
+
+// [a, b] -> [a, b].
+pub fn pair_field(value: &Value, key: &str) -> Result<[usize; 2], Box<dyn Error>> {
+    usize_array(field(value, key)?, key)
+}
+
+// Reject keys of an object outside known, naming the first.
+pub fn check_keys(value: &Value, known: &[&str], what: &str) -> Result<(), Box<dyn Error>> {
+    let object = value
+        .as_object()
+        .ok_or(format!("`{}` should be an object, got {}", what, value))?;
+    match object.keys().find(|k| !known.contains(&k.as_str())) {
+        Some(key) => Err(format!("Unknown field `{}` in {}", key, what).into()),
+        None => Ok(()),
     }
 }
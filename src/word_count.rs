@@ -1,129 +1,301 @@
-use crate::op::{OpTrait, TransOp, VecOp};
-use crate::util::Tik;
-use std::collections::{HashMap, VecDeque};
+use crate::config::{HardwareConfig, WordCountConfig};
+use crate::mapreduce::{Engine, MapReduce, MapReduceJob};
+use crate::partition::{self, LastCharPartitioner, Partitioner};
+use crate::trace::TraceHeader;
+use serde_json::json;
+use std::collections::{BTreeMap, HashMap};
 use std::error::Error;
 use std::fs::File;
 use std::io::{BufRead, BufReader};
 
-pub struct WordCount {
+// Bytes of a count attached to a word in reducer output.
+pub const COUNT_BYTES: usize = 4;
+
+// Lowercase and strip punctuation, None if nothing is left.
+pub fn normalize(word: &str) -> Option<String> {
+    let mut lc_word = word.to_lowercase();
+    lc_word.retain(|c| c != ',' && c != '.');
+    if lc_word.is_empty() {
+        None
+    } else {
+        Some(lc_word)
+    }
+}
+
+// Sequential reference count of a file.
+pub fn reference_count(file_path: &str) -> Result<HashMap<String, usize>, Box<dyn Error>> {
+    let mut counts: HashMap<String, usize> = HashMap::new();
+    for line in BufReader::new(File::open(file_path)?).lines() {
+        for word in line?.split_whitespace().filter_map(normalize) {
+            *counts.entry(word).or_default() += 1;
+        }
+    }
+    Ok(counts)
+}
+
+pub struct WordCountJob {
+    file_path: String,
     reader: BufReader<File>,
-    mapper_buffer: Vec<VecDeque<String>>,
-    reducer_buffer: Vec<VecDeque<String>>,
-    mapper_num: usize,
-    reducer_num: usize,
-    mapper_buf_size: usize,
-    reducer_buf_size: usize,
+    partitioner: Box<dyn Partitioner>,
+    combiner: bool,
     scoreboard: HashMap<String, usize>,
-    pub op_list: Vec<Box<dyn OpTrait>>,
-    tik: Tik,
-    pub mid2pid: Vec<usize>,
-    pub rid2pid: Vec<usize>,
+    // Print every read of a split.
+    verbose: bool,
+}
+
+impl MapReduceJob for WordCountJob {
+    // A chunk of lines.
+    type Split = String;
+    type Key = String;
+    type Value = usize;
+
+    fn next_split(&mut self, max_size: usize) -> Option<(String, usize)> {
+        let mut readouts = String::new();
+        for _ in 0..max_size {
+            match self.reader.read_line(&mut readouts) {
+                Ok(num_bytes) if self.verbose => {
+                    println!("Read {} bytes.", num_bytes);
+                }
+                Err(_) if self.verbose => {
+                    println!("Read file error.");
+                }
+                _ => {}
+            }
+        }
+        if readouts.is_empty() {
+            None
+        } else {
+            let bytes = readouts.len();
+            Some((readouts, bytes))
+        }
+    }
+
+    fn map(&self, split: String) -> Vec<(String, usize)> {
+        split
+            .split_whitespace()
+            .filter_map(normalize)
+            .map(|w| (w, 1))
+            .collect()
+    }
+
+    fn partition(&self, key: &String, reducer_num: usize) -> usize {
+        self.partitioner.partition(key, reducer_num)
+    }
+
+    fn combine(&self, acc: &mut usize, value: usize) {
+        *acc += value;
+    }
+
+    fn reduce(&mut self, _reducer: usize, records: BTreeMap<String, usize>) {
+        for (word, count) in records {
+            *self.scoreboard.entry(word).or_default() += count;
+        }
+    }
+
+    fn use_combiner(&self) -> bool {
+        self.combiner
+    }
+
+    // Combined records carry their count.
+    fn record_bytes(&self, key: &String, _value: &usize) -> usize {
+        if self.combiner {
+            key.len() + COUNT_BYTES
+        } else {
+            key.len()
+        }
+    }
+
+    fn output_bytes(&self, key: &String, _value: &usize) -> usize {
+        key.len() + COUNT_BYTES
+    }
+
+    fn name(&self) -> String {
+        self.partitioner.name()
+    }
 }
 
-impl WordCount {
-    pub fn new(
-        file_path: &'static str,
+pub type WordCount = MapReduce<WordCountJob>;
+
+impl MapReduce<WordCountJob> {
+    pub fn word_count(
+        file_path: &str,
         mapper_num: usize,
         reducer_num: usize,
         mapper_buf_size: usize,
         reducer_buf_size: usize,
     ) -> Result<WordCount, Box<dyn Error>> {
+        if mapper_num == 0 || reducer_num == 0 {
+            return Err("Word count needs at least one mapper and one reducer".into());
+        }
         let f = File::open(file_path)?;
-        let mid2pid = (0..mapper_num).collect::<Vec<usize>>();
-        let rid2pid = (mapper_num..mapper_num + reducer_num).collect::<Vec<usize>>();
-        Ok(WordCount {
+        let job = WordCountJob {
+            file_path: file_path.to_string(),
             reader: BufReader::new(f),
-            mapper_buffer: vec![VecDeque::new(); mapper_num],
-            reducer_buffer: vec![VecDeque::new(); reducer_num],
-            mapper_num,
-            reducer_num,
-            mapper_buf_size,
-            reducer_buf_size,
+            partitioner: Box::new(LastCharPartitioner),
+            combiner: false,
             scoreboard: HashMap::new(),
-            op_list: vec![],
-            tik: Tik::new(),
-            mid2pid,
-            rid2pid,
-        })
-    }
-
-    pub fn fill_mapper(&mut self) -> Result<usize, ()> {
-        let mut bytes_num = 0;
-        for map_idx in 0..self.mapper_num {
-            let words = self.read_file(self.mapper_buf_size - self.mapper_buffer[map_idx].len());
-            if words.len() == 0 {
-                break;
-            }
-            bytes_num += words.len();
-            println!("fill_mapper {}: {} bytes", map_idx, words.len());
-            let op = TransOp::new(
-                self.tik.tik(),
-                -1,
-                map_idx as i32,
-                words.len(),
-                vec![],
-                "Memory to mapper.".to_string(),
-            );
-            self.op_list.push(Box::new(op));
-            self.mapper_buffer[map_idx].push_back(words);
-        }
-        Ok(bytes_num)
+            verbose: false,
+        };
+        let engine = Engine::new(mapper_num, reducer_num, mapper_buf_size, reducer_buf_size);
+        Ok(MapReduce::new(engine, job))
     }
 
-    fn read_file(&mut self, read_size: usize) -> String {
-        let mut readouts = String::new();
-        for _ in 0..read_size {
-            let mut string = String::new();
-            match self.reader.read_line(&mut string) {
-                Ok(num_bytes) => {
-                    println!("Read {} bytes.", num_bytes);
-                }
-                Err(_) => {
-                    println!("Read file error.");
-                }
-            }
-            readouts = readouts + &string;
+    pub fn from_config(
+        hw: &HardwareConfig,
+        wc: &WordCountConfig,
+    ) -> Result<WordCount, Box<dyn Error>> {
+        let mut word_count = WordCount::word_count(
+            &wc.input,
+            hw.mapper_num,
+            hw.reducer_num,
+            wc.mapper_buf_size,
+            wc.reducer_buf_size,
+        )?;
+        word_count.set_partitioner(partition::by_name(
+            &wc.partitioner,
+            &wc.input,
+            hw.reducer_num,
+        )?);
+        word_count.set_combiner(wc.combiner);
+        word_count.set_overflow(wc.overflow);
+        word_count.engine.placement = hw.placement;
+        // Mapped records wait in the mapper's tile sram.
+        for mid in word_count.engine.mids.clone() {
+            word_count.engine.set_sram_size(mid, hw.tile_sram_size);
         }
-        readouts
-    }
-
-    pub fn map(&mut self) {
-        let mut valid = true;
-        while valid {
-            valid = false;
-            for map_idx in 0..self.mapper_num {
-                let line = self.mapper_buffer[map_idx].pop_front();
-                if let Some(line) = line {
-                    let mut tokens: HashMap<usize, Vec<String>> = HashMap::new();
-                    // Perform word splitting.
-                    let words = line.trim().split_whitespace().collect::<Vec<_>>();
-                    // Perform lowering & binning.
-                    for word in words {
-                        let mut lc_word = word.to_lowercase();
-                        lc_word.retain(|c| c != ',' && c != '.');
-                        let last_char = lc_word.chars().last().unwrap() as usize;
-                        let bin_idx = last_char % self.reducer_num;
-                        tokens.entry(bin_idx).or_default().push(lc_word);
-                    }
-                    println!("mapper {}: {:?}", map_idx, &tokens);
-                    // Send tokens to corresponding reducers.
-                    for (r_idx, ts) in tokens.into_iter() {
-                        self.reducer_buffer[r_idx].extend(ts);
-                    }
-                    valid = true
-                }
-            }
+        if hw.amorph_sram {
+            word_count.set_amorph_sram(wc.mapper_spare);
         }
+        Ok(word_count)
     }
 
-    pub fn reduce(&mut self) {
-        for (r_idx, buffer) in self.reducer_buffer.iter_mut().enumerate() {
-            let mut board: HashMap<String, usize> = HashMap::new();
-            while let Some(token) = buffer.pop_front() {
-                board.entry(token).and_modify(|e| *e += 1).or_insert(1);
-            }
-            println!("reducer {}: {:?}", r_idx, &board);
-            self.scoreboard.extend(board);
+    // Pre-aggregate counts per mapper chunk before the shuffle.
+    pub fn set_combiner(&mut self, combiner: bool) {
+        self.job.combiner = combiner;
+    }
+
+    pub fn set_verbose(&mut self, verbose: bool) {
+        self.engine.verbose = verbose;
+        self.job.verbose = verbose;
+    }
+
+    pub fn set_partitioner(&mut self, partitioner: Box<dyn Partitioner>) {
+        self.job.partitioner = partitioner;
+    }
+
+    pub fn trace_header(&self) -> TraceHeader {
+        self.engine.trace_header(json!({
+            "workload": "word-count",
+            "mapper_num": self.engine.mapper_num,
+            "reducer_num": self.engine.reducer_num,
+            "mapper_buf_size": self.engine.mapper_buf_size,
+            "reducer_buf_size": self.engine.reducer_buf_size,
+            "partitioner": self.job.partitioner.name(),
+            "combiner": self.job.combiner,
+            "overflow": format!("{:?}", self.overflow()).to_lowercase(),
+            "amorph_sram": self.amorph_sram(),
+            "mapper_sram": self.engine.mids.first().map(|mid| self.engine.sram_sizes[*mid]),
+            "load": self.load_report().dump2json(),
+        }))
+    }
+
+    pub fn scoreboard(&self) -> &HashMap<String, usize> {
+        &self.job.scoreboard
+    }
+
+    // Compare the scoreboard against a sequential count of the input.
+    pub fn verify(&self) -> Result<(), Box<dyn Error>> {
+        let scoreboard = self.scoreboard();
+        let reference = reference_count(&self.job.file_path)?;
+        let mut mismatches = reference
+            .iter()
+            .filter(|(w, c)| scoreboard.get(*w) != Some(*c))
+            .map(|(w, c)| format!("{}: expected {}, got {:?}", w, c, scoreboard.get(w)))
+            .collect::<Vec<_>>();
+        mismatches.extend(
+            scoreboard
+                .keys()
+                .filter(|w| !reference.contains_key(*w))
+                .map(|w| format!("{}: unexpected word", w)),
+        );
+        if mismatches.is_empty() {
+            Ok(())
+        } else {
+            mismatches.sort();
+            Err(format!("Word count mismatch: {}", mismatches.join("; ")).into())
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mapreduce::Overflow;
+    use crate::partition::HashPartitioner;
+
+    const INPUT: &str = "article/1.txt";
+
+    #[test]
+    fn normalize_words() {
+        assert_eq!(normalize("Hello,"), Some("hello".to_string()));
+        assert_eq!(normalize(",."), None);
+    }
+
+    #[test]
+    fn run_and_verify() {
+        let mut wc = WordCount::word_count(INPUT, 4, 4, 4, 64).unwrap();
+        wc.run();
+        wc.verify().unwrap();
+        assert_eq!(wc.scoreboard(), &reference_count(INPUT).unwrap());
+        assert!(!wc.op_list().is_empty());
+    }
+
+    #[test]
+    fn combiner_keeps_counts() {
+        let mut wc = WordCount::word_count(INPUT, 4, 3, 4, 16).unwrap();
+        wc.set_partitioner(Box::new(HashPartitioner));
+        wc.set_combiner(true);
+        wc.run();
+        wc.verify().unwrap();
+    }
+
+    #[test]
+    fn stall_keeps_counts() {
+        let mut wc = WordCount::word_count(INPUT, 4, 3, 4, 16).unwrap();
+        wc.set_partitioner(Box::new(HashPartitioner));
+        wc.set_overflow(Overflow::Stall);
+        wc.run();
+        wc.verify().unwrap();
+    }
+
+    #[test]
+    fn lending_keeps_counts() {
+        let mut wc = WordCount::word_count(INPUT, 4, 3, 4, 16).unwrap();
+        wc.set_partitioner(Box::new(HashPartitioner));
+        for mid in wc.engine.mids.clone() {
+            wc.engine.set_sram_size(mid, 64);
+        }
+        wc.set_amorph_sram(16);
+        wc.run();
+        wc.verify().unwrap();
+        assert!(wc.load_report().remote.iter().sum::<usize>() > 0);
+        // Mappers keep all but the spare.
+        for mid in wc.engine.mids.iter() {
+            assert!(wc.engine.local_srams[*mid] >= 64 - 16);
+        }
+    }
+
+    #[test]
+    fn load_in_header() {
+        let mut wc = WordCount::word_count(INPUT, 4, 3, 4, 16).unwrap();
+        wc.set_partitioner(Box::new(HashPartitioner));
+        wc.run();
+        let load = &wc.trace_header().generator["load"];
+        assert_eq!(load["policy"], "hash");
+        let records = load["records"].as_array().unwrap();
+        assert_eq!(records.len(), 3);
+        let total = records.iter().map(|r| r.as_u64().unwrap()).sum::<u64>();
+        assert_eq!(total as usize, wc.scoreboard().values().sum::<usize>());
+        assert!(WordCount::word_count(INPUT, 4, 0, 4, 16).is_err());
+    }
+}